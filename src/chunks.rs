@@ -0,0 +1,82 @@
+use bincode::{serialize, deserialize, Infinite};
+
+use key::Key;
+use node::{Node, NodeId, NODEID_BYTELEN};
+
+/// Stored under the original key of a value that was split into chunks.
+/// Lists the keys of the chunks in order, each with the `chunk_hash()` of
+/// the chunk: others may store values under the same chunk keys.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Manifest {
+	pub total_len: u64,
+	pub chunks:    Vec<(Key, NodeId)>,
+}
+
+impl Manifest {
+	pub fn encode(&self) -> Vec<u8> {
+		serialize(self, Infinite).unwrap()
+	}
+
+	pub fn decode(data: &[u8]) -> Option<Manifest> {
+		deserialize(data).ok()
+	}
+}
+
+/// How many chunks a manifest of at most `manifest_len` bytes can list:
+/// total_len and the length of the chunk list take 8 bytes each, each
+/// chunk its key and hash
+pub fn max_chunks(manifest_len: usize) -> usize {
+	manifest_len.saturating_sub(16) / (2*NODEID_BYTELEN)
+}
+
+/// Chunk number `index` of `key` is stored under hash(key || index)
+pub fn chunk_key(key: &Key, index: u64) -> Key {
+	let index:Vec<u8> = (0..8).rev().map(|i| (index >> (8*i)) as u8).collect();
+	Key::new(Node::id_from_components(&[&key.id()[..], &index[..]]))
+}
+
+pub fn chunk_hash(chunk: &[u8]) -> NodeId {
+	Node::id_from_components(&[chunk])
+}
+
+/// Split `value` into chunks of at most `chunk_len` bytes
+pub fn split(key: &Key, value: &[u8], chunk_len: usize) -> (Manifest, Vec<(Key, Vec<u8>)>) {
+	let chunks:Vec<(Key, Vec<u8>)> = value.chunks(chunk_len).enumerate()
+		.map(|(i, c)| (chunk_key(key, i as u64), c.to_vec()))
+		.collect();
+
+	let manifest = Manifest {
+		total_len: value.len() as u64,
+		chunks:    chunks.iter().map(|&(k, ref c)| (k, chunk_hash(c))).collect(),
+	};
+
+	(manifest, chunks)
+}
+
+#[test]
+fn test_split() {
+	let key = Key::new([0x00; NODEID_BYTELEN]);
+	let value = vec![0x42; 5000];
+
+	let (manifest, chunks) = split(&key, &value, 2048);
+	assert_eq!(manifest.total_len, 5000);
+	let keys:Vec<Key> = manifest.chunks.iter().map(|&(k, _)| k).collect();
+	assert_eq!(keys, vec![chunk_key(&key, 0), chunk_key(&key, 1), chunk_key(&key, 2)]);
+
+	let lens:Vec<usize> = chunks.iter().map(|&(_, ref c)| c.len()).collect();
+	assert_eq!(lens, vec![2048, 2048, 904]);
+	assert_eq!(manifest.chunks[2].1, chunk_hash(&value[..904]));
+
+	assert_eq!(Manifest::decode(&manifest.encode()[..]), Some(manifest));
+}
+
+#[test]
+fn test_max_chunks() {
+	let key = Key::new([0x00; NODEID_BYTELEN]);
+	let n = max_chunks(2048);
+
+	let (manifest, _) = split(&key, &vec![0x42; n], 1);
+	assert!(manifest.encode().len() <= 2048);
+	let (manifest, _) = split(&key, &vec![0x42; n+1], 1);
+	assert!(manifest.encode().len() > 2048);
+}
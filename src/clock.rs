@@ -0,0 +1,75 @@
+//! All durations, instants and sleeps come from here, so that time-based
+//! logic (storage expiry, republishing, refreshing) can run against a
+//! `MockClock` in tests.
+
+use std::sync::{Arc,Mutex};
+use std::thread;
+
+pub use std::time::{Duration,Instant};
+
+pub trait Clock: Send + Sync {
+	fn now(&self) -> Instant;
+	fn sleep(&self, dur: Duration);
+}
+
+pub type SharedClock = Arc<Clock>;
+
+/// The wall clock
+#[derive(Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+
+	fn sleep(&self, dur: Duration) {
+		thread::sleep(dur)
+	}
+}
+
+pub fn system() -> SharedClock {
+	Arc::new(SystemClock)
+}
+
+/// A clock that only moves forward if you tell it to (or if someone sleeps).
+#[derive(Clone)]
+pub struct MockClock {
+	now: Arc<Mutex<Instant>>,
+}
+
+#[allow(dead_code)]
+impl MockClock {
+	pub fn new() -> MockClock {
+		MockClock {
+			now: Arc::new(Mutex::new(Instant::now())),
+		}
+	}
+
+	pub fn advance(&self, dur: Duration) {
+		let mut now = self.now.lock().unwrap();
+		*now = *now + dur;
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> Instant {
+		*self.now.lock().unwrap()
+	}
+
+	fn sleep(&self, dur: Duration) {
+		self.advance(dur)
+	}
+}
+
+#[test]
+fn mock_advance() {
+	let clock = MockClock::new();
+	let start = clock.now();
+
+	clock.advance(Duration::from_secs(10));
+	assert_eq!(clock.now(), start + Duration::from_secs(10));
+
+	clock.sleep(Duration::from_secs(5));
+	assert_eq!(clock.now(), start + Duration::from_secs(15));
+}
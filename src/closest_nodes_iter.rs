@@ -1,31 +1,109 @@
 use std::sync::{Arc, Mutex, Condvar};
 use std::sync::mpsc::Receiver;
 use std::thread::spawn;
+use std::collections::HashMap;
 
 use node::{Node, NodeId};
 
 #[cfg(test)]
 use node::NODEID_BYTELEN;
 
+/// A contact offered more often than this is ignored
+const MAX_OFFERS: usize = 3;
+/// After <count>*STALE_FACTOR new nodes in a row that did not get into the
+/// <count> closest, only the remaining ones of the <count> closest are asked
+const STALE_FACTOR: usize = 3;
+
+/// Detects lookups that do not converge (e.g. if a malicious node always
+/// returns itself or the same few nodes)
+struct Progress {
+	count:   usize,
+	offers:  HashMap<NodeId, usize>,
+	closest: Vec<NodeId>, // distances of the <count> closest nodes so far, ascending
+	stale:   usize, // new nodes in a row that did not get into `closest`
+}
+
+impl Progress {
+	/// Our own contacts the lookup starts with: they are among the closest
+	/// so far, but only nodes learned from responses count as offers
+	fn seed(&mut self, key: &NodeId, node: &Node) {
+		self.improve(node.dist(key));
+	}
+
+	/// Returns false if the node was offered too often already. Only nodes
+	/// we did not know yet (`known`: processed or a candidate) count towards
+	/// `stale`.
+	fn offer(&mut self, key: &NodeId, node: &Node, known: bool) -> bool {
+		let offers = self.offers.entry(node.node_id).or_insert(0);
+		if !known && *offers == 0 {
+			if self.improve(node.dist(key)) {
+				self.stale = 0;
+			} else {
+				self.stale += 1;
+			}
+		}
+
+		*offers += 1;
+		*offers <= MAX_OFFERS
+	}
+
+	/// Adds `dist` to the closest distances if it gets in
+	fn improve(&mut self, dist: NodeId) -> bool {
+		if self.closest.contains(&dist) || !self.is_closest(&dist) {
+			return false;
+		}
+
+		let pos = self.closest.iter().position(|d| dist < *d).unwrap_or(self.closest.len());
+		self.closest.insert(pos, dist);
+		self.closest.truncate(self.count);
+		true
+	}
+
+	fn is_closest(&self, dist: &NodeId) -> bool {
+		self.closest.len() < self.count || self.closest.last().map(|d| dist <= d).unwrap_or(true)
+	}
+
+	fn is_stale(&self) -> bool {
+		self.stale >= self.count*STALE_FACTOR
+	}
+}
+
 #[derive(Clone)]
 pub struct ClosestNodesIter {
 	key: Arc<NodeId>,
 	count: usize, // ask at least <count> nodes
 	processed_nodes: Arc<Mutex<Vec<Node>>>,
 	unprocessed_nodes: Arc<(Mutex<(Vec<Node>, usize)>, Condvar)>,
+	progress: Arc<Mutex<Progress>>,
 }
 
 impl ClosestNodesIter {
 	pub fn new(key: NodeId, count: usize, node_list: Vec<Node>) -> ClosestNodesIter {
-		let this = ClosestNodesIter {
+		let mut progress = Progress {
+			count:   count,
+			offers:  HashMap::new(),
+			closest: vec![],
+			stale:   0,
+		};
+		for n in node_list.iter() {
+			progress.seed(&key, n);
+		}
+
+		let mut nodes = node_list;
+		{
+			let key = &key;
+			nodes.sort_by(asc_dist_order!(key));
+		}
+		nodes.dedup();
+		nodes.truncate(count);
+
+		ClosestNodesIter {
 			key:               Arc::new(key),
 			count:             count,
 			processed_nodes:   Arc::new(Mutex::new(vec![])),
-			unprocessed_nodes: Arc::new((Mutex::new((vec![], 0)), Condvar::new())),
-		};
-
-		this.add_nodes(node_list);
-		this
+			unprocessed_nodes: Arc::new((Mutex::new((nodes, 0)), Condvar::new())),
+			progress:          Arc::new(Mutex::new(progress)),
+		}
 	}
 
 	#[allow(dead_code)]
@@ -57,9 +135,13 @@ impl ClosestNodesIter {
 		let &mut(ref mut unprocessed_nodes, _) = &mut *pair;
 
 		// add nodes
+		let mut progress = self.progress.lock().unwrap();
 		let iter = node_list.iter().filter(|n| !processed_nodes.contains(n));
 		for n in iter {
-			unprocessed_nodes.push(n.clone());
+			let known = unprocessed_nodes.contains(n);
+			if progress.offer(&self.key, n, known) {
+				unprocessed_nodes.push(n.clone());
+			}
 		}
 
 		// sort nodes
@@ -82,7 +164,9 @@ impl ClosestNodesIter {
 		let &mut(ref mut unprocessed_nodes, _) = &mut *pair;
 
 		// add nodes
-		if !processed_nodes.contains(&node) {
+		let mut progress = self.progress.lock().unwrap();
+		let known = unprocessed_nodes.contains(&node);
+		if !processed_nodes.contains(&node) && progress.offer(&self.key, &node, known) {
 			unprocessed_nodes.push(node);
 		}
 
@@ -162,6 +246,12 @@ impl Iterator for ClosestNodesIter {
 			match unprocessed_nodes.pop() {
 				None => return None,
 				Some(node) => {
+					let progress = self.progress.lock().unwrap();
+					if progress.is_stale() && !progress.is_closest(&node.dist(key)) {
+						// the remaining candidates are farther than the <count> closest
+						debug!("lookup does not make any progress");
+						return None;
+					}
 					processed_nodes.push(node.clone());
 
 					if closest_dist.map(|dist| node.dist(key) >= dist).unwrap_or(false)
@@ -227,3 +317,71 @@ fn order() {
 		assert_eq!(iter.next(), None);
 	}
 }
+
+#[test]
+fn no_progress() {
+	let key = [0; NODEID_BYTELEN];
+	let count = 2;
+
+	let node0x01 = Node::new("127.0.0.1:2134", [0x01; NODEID_BYTELEN]).unwrap();
+	let mut iter = ClosestNodesIter::new(key, count, vec![node0x01.clone()]);
+	assert_eq!(iter.next(), Some(node0x01));
+
+	let node0x02 = Node::new("127.0.0.1:2134", [0x02; NODEID_BYTELEN]).unwrap();
+	iter.add_node(node0x02.clone());
+
+	// nodes that never get into the closest ones
+	for i in 0..count*STALE_FACTOR {
+		let node = Node::new("127.0.0.1:2134", [0x80 + i as u8; NODEID_BYTELEN]).unwrap();
+		iter.add_node(node);
+	}
+	assert!(iter.progress.lock().unwrap().is_stale());
+
+	// the closest ones are still asked
+	assert_eq!(iter.next(), Some(node0x02));
+	assert_eq!(iter.next(), None);
+}
+
+#[test]
+fn honest_convergence() {
+	let key = [0; NODEID_BYTELEN];
+	let count = 3;
+	let node = |i| Node::new("127.0.0.1:2134", [i; NODEID_BYTELEN]).unwrap();
+
+	// every node knows the whole network and answers with the 3 closest
+	let closest = vec![node(0x01), node(0x02), node(0x03)];
+	let seeds = vec![node(0x08), node(0x09), node(0x0a), node(0x0b)];
+	let mut iter = ClosestNodesIter::new(key, count, seeds);
+
+	// alpha queries in flight, their answers repeat the same nodes
+	let mut asked = vec![];
+	for _ in 0..3 {
+		asked.push(iter.next().unwrap());
+	}
+	for _ in 0..3 {
+		iter.add_nodes(closest.clone());
+	}
+	while let Some(n) = iter.next() {
+		asked.push(n);
+		iter.add_nodes(closest.clone());
+	}
+
+	for n in closest.iter() {
+		assert!(asked.contains(n));
+	}
+}
+
+#[test]
+fn many_seeds() {
+	let key = [0; NODEID_BYTELEN];
+	let count = 2;
+
+	// none of them is closer than the first, but they are our own contacts
+	let seeds:Vec<Node> = (1..(count*STALE_FACTOR*4) as u8)
+		.map(|i| Node::new("127.0.0.1:2134", [i; NODEID_BYTELEN]).unwrap())
+		.collect();
+	let mut iter = ClosestNodesIter::new(key, count, seeds.clone());
+
+	assert_eq!(iter.next(), Some(seeds[0].clone()));
+	assert_eq!(iter.next(), Some(seeds[1].clone()));
+}
@@ -0,0 +1,194 @@
+use std::io;
+use std::cell::Cell;
+use std::sync::Arc;
+
+use bincode::{serialize, deserialize_from, Bounded};
+use serde_bencode;
+
+use config::WireFormat;
+use message::{Message, PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION};
+
+/// Upper bound for encoded messages, in both directions and both formats
+pub const MAX_MESSAGE_LEN: usize = 2048;
+
+/// Turns messages into datagrams and back
+pub trait Codec: Send + Sync {
+	fn encode(&self, msg: &Message) -> io::Result<Vec<u8>>;
+	fn decode(&self, buf: &[u8]) -> io::Result<Message>;
+}
+
+pub type SharedCodec = Arc<Codec>;
+
+/// The native bincode format
+pub struct NativeCodec;
+
+/// Bencoded messages, readable by BitTorrent DHT tooling
+pub struct BencodeCodec;
+
+impl Codec for NativeCodec {
+	fn encode(&self, msg: &Message) -> io::Result<Vec<u8>> {
+		serialize(msg, Bounded(MAX_MESSAGE_LEN as u64))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+	}
+
+	fn decode(&self, buf: &[u8]) -> io::Result<Message> {
+		// the bound keeps forged length prefixes from allocating
+		let mut reader = buf;
+		deserialize_from(&mut reader, Bounded(MAX_MESSAGE_LEN as u64))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+	}
+}
+
+thread_local!(static BYTE_IDS: Cell<bool> = Cell::new(false));
+
+/// Are ids (de)serialized as byte strings, see `message::id_bytes`?
+/// Only while `BencodeCodec` is at it, so the native format (and the
+/// saved routing table) keep their fixed arrays.
+pub fn byte_ids() -> bool {
+	BYTE_IDS.with(|byte_ids| byte_ids.get())
+}
+
+fn with_byte_ids<T, F: FnOnce() -> T>(f: F) -> T {
+	BYTE_IDS.with(|byte_ids| byte_ids.set(true));
+	let result = f();
+	BYTE_IDS.with(|byte_ids| byte_ids.set(false));
+	result
+}
+
+impl Codec for BencodeCodec {
+	fn encode(&self, msg: &Message) -> io::Result<Vec<u8>> {
+		let buf = try!(with_byte_ids(|| serde_bencode::ser::to_bytes(msg))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))));
+
+		if buf.len() > MAX_MESSAGE_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too large"));
+		}
+		Ok(buf)
+	}
+
+	fn decode(&self, buf: &[u8]) -> io::Result<Message> {
+		if buf.len() > MAX_MESSAGE_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+		}
+
+		with_byte_ids(|| serde_bencode::de::from_bytes(buf))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))
+	}
+}
+
+/// The version prefix is `VERSION_MARKER | version` (for versions up to 15).
+/// Encoded messages never start like this (bincode starts with the small
+/// enum tag, bencode with 'd'), so version 1 datagrams without a prefix
+/// can be told apart and dropped.
+pub const VERSION_MARKER: u8 = 0xB0;
+
+/// Prefix an encoded message with our protocol version
+pub fn add_version(buf: Vec<u8>) -> Vec<u8> {
+	let mut datagram = Vec::with_capacity(buf.len() + 1);
+	datagram.push(VERSION_MARKER | PROTOCOL_VERSION);
+	datagram.extend(buf);
+	datagram
+}
+
+/// Split a datagram into the sender's protocol version and the encoded
+/// message, None if it is empty. Datagrams without a prefix are of
+/// `LEGACY_PROTOCOL_VERSION`.
+pub fn split_version(datagram: &[u8]) -> Option<(u8, &[u8])> {
+	match datagram.split_first() {
+		None => None,
+		Some((&first, buf)) if first & 0xF0 == VERSION_MARKER => Some((first & 0x0F, buf)),
+		Some(_) => Some((LEGACY_PROTOCOL_VERSION, datagram)),
+	}
+}
+
+pub fn for_format(format: WireFormat) -> SharedCodec {
+	match format {
+		WireFormat::Native => Arc::new(NativeCodec),
+		WireFormat::Bencode => Arc::new(BencodeCodec),
+	}
+}
+
+#[cfg(test)]
+use serde_bencode::value::Value;
+#[cfg(test)]
+use message::FindNode;
+#[cfg(test)]
+use node::NODEID_BYTELEN;
+
+#[test]
+fn bencode_find_node() {
+	let msg = Message::FindNode(FindNode {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+		key:       [0x03; NODEID_BYTELEN],
+	});
+
+	let buf = BencodeCodec.encode(&msg).unwrap();
+
+	// any bencode parser can read it, the ids are byte strings
+	let find_node = match serde_bencode::de::from_bytes::<Value>(&buf[..]).unwrap() {
+		Value::Dict(mut dict) => dict.remove(&b"FindNode"[..].to_vec()).unwrap(),
+		_ => unreachable!(),
+	};
+	match find_node {
+		Value::Dict(dict) => assert_eq!(dict.get(&b"key"[..].to_vec()),
+			Some(&Value::Bytes(vec![0x03; NODEID_BYTELEN]))),
+		_ => unreachable!(),
+	}
+
+	assert_eq!(BencodeCodec.decode(&buf[..]).unwrap(), msg);
+	assert!(NativeCodec.decode(&buf[..]).map(|m| m != msg).unwrap_or(true));
+}
+
+#[test]
+fn version_prefix() {
+	let datagram = add_version(vec![1,2,3]);
+	assert_eq!(datagram, vec![VERSION_MARKER | PROTOCOL_VERSION, 1,2,3]);
+	assert_eq!(split_version(&datagram[..]), Some((PROTOCOL_VERSION, &[1,2,3][..])));
+	assert_eq!(split_version(&[]), None);
+
+	// a version 1 FindNode, its bincode tag is 2 as well
+	let msg = Message::FindNode(FindNode {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+		key:       [0x03; NODEID_BYTELEN],
+	});
+	for legacy in vec![NativeCodec.encode(&msg).unwrap(), BencodeCodec.encode(&msg).unwrap()] {
+		assert_eq!(split_version(&legacy[..]), Some((LEGACY_PROTOCOL_VERSION, &legacy[..])));
+	}
+}
+
+#[test]
+fn bounded_decoding() {
+	let msg = Message::FindNode(FindNode {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+		key:       [0x03; NODEID_BYTELEN],
+	});
+	let mut buf = BencodeCodec.encode(&msg).unwrap();
+	buf.extend(vec![b' '; MAX_MESSAGE_LEN]);
+	assert_eq!(BencodeCodec.decode(&buf[..]).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+	// a FindValues whose keys claim to be 4 GiB long
+	let mut buf = vec![9,0,0,0];
+	buf.extend(vec![0; 2*NODEID_BYTELEN]);
+	buf.extend(vec![0,0,0,0,1,0,0,0]);
+	assert!(NativeCodec.decode(&buf[..]).is_err());
+}
+
+#[test]
+fn native_ids() {
+	let msg = Message::FindNode(FindNode {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+		key:       [0x03; NODEID_BYTELEN],
+	});
+
+	// the ids are fixed arrays like in version 1, without length prefix
+	let mut expected = vec![2,0,0,0];
+	expected.extend(vec![0x01; NODEID_BYTELEN]);
+	expected.extend(vec![0x02; NODEID_BYTELEN]);
+	expected.extend(vec![0x03; NODEID_BYTELEN]);
+	assert_eq!(NativeCodec.encode(&msg).unwrap(), expected);
+	assert_eq!(NativeCodec.decode(&expected[..]).unwrap(), msg);
+}
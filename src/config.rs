@@ -0,0 +1,279 @@
+use std::io;
+use std::net::SocketAddr;
+
+use clock::Duration;
+use codec;
+use node::{Node, Distance, NODEID_BYTELEN};
+use message::{Message, FoundNode, MAX_BATCH_TOTAL_LEN};
+use kademlia::{K_PARAM, ALPHA_PARAM, TIMEOUT_MS, MAX_VALUE_LEN, TTL_SECS};
+
+/// What `find_value` returns if nodes disagree on the values for a key
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy {
+	/// every distinct value
+	All,
+	/// only the value returned by the most nodes
+	Majority,
+	/// only the first value that arrives
+	First,
+}
+
+/// What a node does when it receives a `Store` with an empty value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmptyValuePolicy {
+	/// store it like any other value
+	Store,
+	/// drop the message
+	Reject,
+	/// treat it as a tombstone: remove all values stored for the key
+	Delete,
+}
+
+/// What a node does with a `Store` for a key it is not among the K
+/// closest nodes for (as far as it knows)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FarStorePolicy {
+	/// store it anyway
+	Accept,
+	/// drop the message
+	Reject,
+	/// pass it on to the closest nodes we know instead of storing it
+	/// (see `MAX_STORE_HOPS`)
+	Forward,
+}
+
+/// Which addresses of contacts go into the routing table. Unspecified,
+/// broadcast, link-local, ... addresses never do.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressPolicy {
+	/// only globally routable addresses
+	GlobalOnly,
+	/// private addresses, too, e.g. for a network within a LAN
+	AllowPrivate,
+	/// private and loopback addresses, e.g. to run several nodes on one machine
+	AllowLocal,
+}
+
+impl AddressPolicy {
+	pub fn accepts(&self, addr: &SocketAddr) -> bool {
+		match *self {
+			AddressPolicy::GlobalOnly => !Node::is_private(addr) && !Node::is_loopback(addr),
+			AddressPolicy::AllowPrivate => !Node::is_loopback(addr),
+			AddressPolicy::AllowLocal => true,
+		}
+	}
+}
+
+/// How messages are encoded on the wire, see `codec`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WireFormat {
+	/// bincode
+	Native,
+	/// bencode, for interoperability with BitTorrent DHT tooling
+	Bencode,
+}
+
+/// Tunables of a `Kademlia` node.
+///
+/// Use `KademliaConfig::default()` and override the fields you care about.
+#[derive(Clone, Debug)]
+pub struct KademliaConfig {
+	/// Bucket size and number of nodes a lookup converges to, at most `max_k()`
+	pub k: usize,
+
+	/// Number of concurrent requests during a lookup
+	pub alpha: isize,
+
+	/// How long to wait for a response to a request
+	pub timeout_ms: u32,
+
+	/// Maximum size of a single value
+	pub max_value_len: usize,
+
+	/// How long other nodes keep a value we stored
+	pub ttl: Duration,
+
+	/// Stop accumulating values in a single `find_value` lookup once their
+	/// total size would exceed this many bytes.
+	pub max_lookup_value_bytes: usize,
+
+	/// Answer a FindValue with at most this many values (the most recently
+	/// stored ones).
+	pub max_values_per_response: usize,
+
+	/// See `ConflictPolicy`
+	pub conflict_policy: ConflictPolicy,
+
+	/// See `EmptyValuePolicy`
+	pub empty_value_policy: EmptyValuePolicy,
+
+	/// See `FarStorePolicy`
+	pub far_store_policy: FarStorePolicy,
+
+	/// Only store values on nodes within this distance of the key,
+	/// even if they are among the K closest nodes we found
+	pub max_replication_distance: Option<Distance>,
+
+	/// Let `put` and `put_with_mode` split values larger than `max_value_len`
+	/// into chunks (see `put_large`) instead of failing with
+	/// `PutError::TooLarge`. The other put variants always fail.
+	pub auto_chunk: bool,
+
+	/// Drop incoming messages while this many are still waiting to be handled
+	pub max_pending_messages: usize,
+
+	/// Number of threads handling incoming messages
+	pub handler_threads: usize,
+
+	/// Upper bound for `Kademlia::thread_count()` (the handler threads, peer
+	/// watchers and `get_many` workers). `watch_peer` fails while it is
+	/// reached, `get_many` starts fewer workers or looks the keys up one by one.
+	pub max_threads: usize,
+
+	/// Abort lookups (returning what they found so far) once they sent and
+	/// received more than this many bytes
+	pub max_lookup_bytes: Option<usize>,
+
+	/// Abort lookups (returning what they found so far) once they ran for
+	/// this long or sent this many queries
+	pub lookup_timeout:     Duration,
+	pub max_lookup_queries: usize,
+
+	/// How often holders of a value may extend its TTL among themselves
+	/// once its publisher stopped republishing it (0 disables this)
+	pub max_holder_refreshes: usize,
+
+	/// Ping contacts we did not hear from for this long (checked every
+	/// `REFRESH_INTERVAL_SECS`) and evict them if they do not answer
+	pub stale_after: Option<Duration>,
+
+	/// How often `bootstrap` looks itself up (with a new NodeId if the
+	/// last one was taken) before giving up
+	pub max_bootstrap_attempts: usize,
+
+	/// How many keys and bytes a single IP address may store on this node
+	pub max_keys_per_source:  usize,
+	pub max_bytes_per_source: usize,
+
+	/// Messages per second we handle from a single IP address (0 disables
+	/// the limit), with bursts of up to `msg_burst_per_source`
+	pub msg_rate_per_source:  u32,
+	pub msg_burst_per_source: u32,
+
+	/// Which contacts we accept into the routing table (besides never
+	/// adding ourselves). `AllowPrivate` by default, so networks within a
+	/// LAN keep working.
+	pub address_policy: AddressPolicy,
+
+	/// Reject unsigned values, both in Stores and in lookup results
+	/// (see `Kademlia::put_signed()`)
+	pub require_signatures: bool,
+
+	/// All nodes of a network must use the same format
+	pub wire_format: WireFormat,
+}
+
+impl Default for KademliaConfig {
+	fn default() -> KademliaConfig {
+		KademliaConfig {
+			k: K_PARAM,
+			alpha: ALPHA_PARAM,
+			timeout_ms: TIMEOUT_MS,
+			max_value_len: MAX_VALUE_LEN,
+			ttl: Duration::from_secs(TTL_SECS),
+			max_lookup_value_bytes: 256*1024,
+			max_values_per_response: 32,
+			conflict_policy: ConflictPolicy::All,
+			empty_value_policy: EmptyValuePolicy::Store,
+			far_store_policy: FarStorePolicy::Accept,
+			max_replication_distance: None,
+			auto_chunk: false,
+			max_pending_messages: 256,
+			handler_threads: 8,
+			max_threads: 512,
+			max_lookup_bytes: None,
+			lookup_timeout: Duration::from_secs(10),
+			max_lookup_queries: 128,
+			max_holder_refreshes: 0,
+			stale_after: None,
+			max_bootstrap_attempts: 20,
+			max_keys_per_source: 1024,
+			max_bytes_per_source: 1024*1024,
+			msg_rate_per_source: 100,
+			msg_burst_per_source: 200,
+			address_policy: AddressPolicy::AllowPrivate,
+			require_signatures: false,
+			wire_format: WireFormat::Native,
+		}
+	}
+}
+
+/// Largest `k` for `format`: the k FoundNode datagrams answering a single
+/// FindNode stay within `MAX_BATCH_TOTAL_LEN` bytes, like the FoundValues
+/// answering a FindValues (about 40 with the native format)
+pub fn max_k(format: WireFormat) -> usize {
+	// the longest address there is
+	let contact = Node::new("[2001:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535", [0xff; NODEID_BYTELEN])
+		.unwrap();
+	let found_node = Message::FoundNode(FoundNode {
+		sender_id:  [0xff; NODEID_BYTELEN],
+		cookie:     [0xff; NODEID_BYTELEN],
+		node_count: K_PARAM,
+		node:       contact,
+	});
+
+	let len = codec::for_format(format).encode(&found_node)
+		.map(|buf| codec::add_version(buf).len())
+		.expect("a FoundNode always fits into a datagram");
+	MAX_BATCH_TOTAL_LEN / len
+}
+
+impl KademliaConfig {
+	pub fn validate(&self) -> io::Result<()> {
+		if self.k == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "k must be at least 1"));
+		}
+		if self.k > max_k(self.wire_format) {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				format!("k must be at most {} with {:?} messages", max_k(self.wire_format), self.wire_format)));
+		}
+		if self.alpha < 1 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha must be at least 1"));
+		}
+		if self.handler_threads < 1 || self.handler_threads > self.max_threads {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				"handler_threads must be between 1 and max_threads"));
+		}
+		if self.max_pending_messages == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "max_pending_messages must be at least 1"));
+		}
+		if self.max_bootstrap_attempts == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "max_bootstrap_attempts must be at least 1"));
+		}
+		if self.timeout_ms == 0 || self.lookup_timeout == Duration::from_secs(0) {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "timeouts must not be zero"));
+		}
+		Ok(())
+	}
+}
+
+#[test]
+fn validate() {
+	assert!(KademliaConfig::default().validate().is_ok());
+	assert!(KademliaConfig { k: 40, .. KademliaConfig::default() }.validate().is_ok());
+
+	let invalid = vec![
+		KademliaConfig { k: 0, .. KademliaConfig::default() },
+		KademliaConfig { k: max_k(WireFormat::Native) + 1, .. KademliaConfig::default() },
+		KademliaConfig { k: max_k(WireFormat::Bencode) + 1, wire_format: WireFormat::Bencode,
+			.. KademliaConfig::default() },
+		KademliaConfig { handler_threads: 0, .. KademliaConfig::default() },
+		KademliaConfig { max_pending_messages: 0, .. KademliaConfig::default() },
+		KademliaConfig { max_bootstrap_attempts: 0, .. KademliaConfig::default() },
+		KademliaConfig { timeout_ms: 0, .. KademliaConfig::default() },
+		KademliaConfig { lookup_timeout: Duration::from_secs(0), .. KademliaConfig::default() },
+	];
+	for config in invalid {
+		assert_eq!(config.validate().map_err(|e| e.kind()), Err(io::ErrorKind::InvalidInput));
+	}
+}
@@ -0,0 +1,167 @@
+use std::sync::{Arc,Mutex};
+use std::collections::HashSet;
+
+use clock::{self, Instant, SharedClock};
+use node::NodeId;
+
+/// Network traffic caused by a lookup
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LookupCost {
+	pub bytes_sent:     usize,
+	pub bytes_received: usize,
+	pub queries:        usize,
+	/// number of distinct nodes that answered
+	pub nodes_reached:  usize,
+	/// time-weighted average number of queries in flight. Compare it
+	/// to alpha to see how much of the allowed parallelism was used.
+	pub avg_in_flight:  f64,
+}
+
+impl LookupCost {
+	pub fn total_bytes(&self) -> usize {
+		self.bytes_sent + self.bytes_received
+	}
+}
+
+struct InFlight {
+	started:     Instant,
+	last_change: Instant,
+	count:       usize,
+	/// integral of `count` over time, in query-seconds
+	area:        f64,
+}
+
+impl InFlight {
+	fn advance(&mut self, now: Instant) {
+		let dt = now.duration_since(self.last_change);
+		let dt = dt.as_secs() as f64 + dt.subsec_nanos() as f64 / 1e9;
+
+		self.area += self.count as f64 * dt;
+		self.last_change = now;
+	}
+}
+
+/// Accounts the `LookupCost` of a lookup, shared with its threads
+#[derive(Clone)]
+pub struct CostMeter {
+	cost:      Arc<Mutex<LookupCost>>,
+	reached:   Arc<Mutex<HashSet<NodeId>>>,
+	in_flight: Arc<Mutex<InFlight>>,
+	max_bytes: Option<usize>,
+	clock:     SharedClock,
+}
+
+impl CostMeter {
+	pub fn new(max_bytes: Option<usize>) -> CostMeter {
+		Self::with_clock(max_bytes, clock::system())
+	}
+
+	pub fn with_clock(max_bytes: Option<usize>, clock: SharedClock) -> CostMeter {
+		let now = clock.now();
+
+		CostMeter {
+			cost:      Arc::new(Mutex::new(LookupCost::default())),
+			reached:   Arc::new(Mutex::new(HashSet::new())),
+			in_flight: Arc::new(Mutex::new(InFlight {
+				started:     now,
+				last_change: now,
+				count:       0,
+				area:        0.0,
+			})),
+			max_bytes: max_bytes,
+			clock:     clock,
+		}
+	}
+
+	pub fn query(&self, len: usize) {
+		let mut cost = self.cost.lock().unwrap();
+		cost.bytes_sent += len;
+		cost.queries += 1;
+	}
+
+	pub fn response(&self, from: &NodeId, len: usize) {
+		self.cost.lock().unwrap().bytes_received += len;
+		self.reached.lock().unwrap().insert(*from);
+	}
+
+	/// A query was sent, see `Server::send_many_request_metered()`
+	pub fn query_started(&self) {
+		let mut in_flight = self.in_flight.lock().unwrap();
+		in_flight.advance(self.clock.now());
+		in_flight.count += 1;
+	}
+
+	/// A query got all its responses or timed out
+	pub fn query_finished(&self) {
+		let mut in_flight = self.in_flight.lock().unwrap();
+		in_flight.advance(self.clock.now());
+		in_flight.count -= 1;
+	}
+
+	/// True once the lookup sent and received more than `max_bytes`
+	pub fn exceeded(&self) -> bool {
+		self.max_bytes
+			.map(|max| self.cost().total_bytes() > max)
+			.unwrap_or(false)
+	}
+
+	pub fn cost(&self) -> LookupCost {
+		let mut cost = *self.cost.lock().unwrap();
+		cost.nodes_reached = self.reached.lock().unwrap().len();
+
+		let mut in_flight = self.in_flight.lock().unwrap();
+		in_flight.advance(self.clock.now());
+
+		let elapsed = in_flight.last_change.duration_since(in_flight.started);
+		let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+		if elapsed > 0.0 {
+			cost.avg_in_flight = in_flight.area / elapsed;
+		}
+
+		cost
+	}
+}
+
+#[cfg(test)]
+use clock::{Duration, MockClock};
+
+#[test]
+fn budget() {
+	let meter = CostMeter::new(Some(100));
+
+	meter.query(60);
+	meter.response(&[0x01; 20], 40);
+	assert!(!meter.exceeded());
+
+	meter.response(&[0x01; 20], 1);
+	assert!(meter.exceeded());
+	assert_eq!(meter.cost(), LookupCost {
+		bytes_sent:     60,
+		bytes_received: 41,
+		queries:        1,
+		nodes_reached:  1,
+		avg_in_flight:  0.0,
+	});
+
+	assert!(!CostMeter::new(None).exceeded());
+}
+
+#[test]
+fn in_flight() {
+	let clock = MockClock::new();
+	let meter = CostMeter::with_clock(None, Arc::new(clock.clone()));
+
+	meter.query_started();
+	clock.advance(Duration::from_millis(10));
+	meter.query_started();
+	clock.advance(Duration::from_millis(10));
+	meter.query_finished();
+	meter.query_finished();
+
+	// one query for 10ms, then two for 10ms
+	assert!((meter.cost().avg_in_flight - 1.5).abs() < 1e-6);
+
+	// idle time counts, too
+	clock.advance(Duration::from_millis(20));
+	assert!((meter.cost().avg_in_flight - 0.75).abs() < 1e-6);
+}
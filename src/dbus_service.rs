@@ -12,7 +12,8 @@ use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
 use kademlia::Kademlia;
-use node::{NodeId, NODEID_BYTELEN};
+use key::Key;
+use node::NODEID_BYTELEN;
 
 fn message_item_to_u64(item: MessageItem) -> Result<u64, (&'static str, String)> {
 	match item {
@@ -62,7 +63,7 @@ fn byte_vec_to_message_item(vec: Vec<u8>) -> MessageItem {
 	MessageItem::Array(items, Cow::Borrowed("y"))
 }
 
-fn hash(app_id: String, data: &[u8]) -> NodeId {
+fn hash(app_id: String, data: &[u8]) -> Key {
 	let mut hasher = Sha1::new();
 
 	let mut output = vec![0x0; hasher.output_bytes()];
@@ -72,7 +73,7 @@ fn hash(app_id: String, data: &[u8]) -> NodeId {
 
         let mut hash = [0u8; NODEID_BYTELEN];
 	hash.clone_from_slice(&output[..NODEID_BYTELEN]);
-	hash
+	Key::new(hash)
 }
 
 fn dht_get(kad: Kademlia, app_id: MessageItem, key: MessageItem)
@@ -101,7 +102,7 @@ fn dht_put(mut kad: Kademlia, app_id: MessageItem, key: MessageItem, value: Mess
 
 	kad.put(hash_key, value)
 		.map(|_| vec![])
-		.map_err(|_| ("org.manuel.Intercom.PutFailed", "Put failed".to_string()))
+		.map_err(|e| ("org.manuel.Intercom.PutFailed", format!("Put failed: {:?}", e)))
 }
 
 fn dht_store(mut kad: Kademlia, app_id: MessageItem, key: MessageItem, value: MessageItem, lifetime: MessageItem)
@@ -185,6 +186,8 @@ mod tests {
 
 	use node::NODEID_BYTELEN;
 	use kademlia::Kademlia;
+	use config::{KademliaConfig, AddressPolicy};
+	use key::Key;
 
 	use tokio_core::reactor::Core;
 	use tokio_core::reactor::Handle;
@@ -204,9 +207,14 @@ mod tests {
 		let handle = core.handle();
 
 		let super_addr = ("127.0.0.1", 20000);
-		let _ = Kademlia::new_supernode(handle.clone(), super_addr, Some(zeros.clone()));
-
-		let kad = Kademlia::bootstrap(handle.clone(), "127.0.0.1:20001", vec![super_addr], Some(ones.clone()));
+		let config = KademliaConfig {
+			address_policy: AddressPolicy::AllowLocal,
+			.. KademliaConfig::default()
+		};
+		let _ = Kademlia::create_with_config(handle.clone(), super_addr, Some(zeros.clone()), config.clone()).unwrap();
+
+		let kad = Kademlia::bootstrap_with_config(handle.clone(), "127.0.0.1:20001", vec![super_addr],
+			Some(ones.clone()), config).unwrap();
 
 		let dbus_name = "org.manuel.BulletinBoardTest1";
 		let name = dbus_name.clone();
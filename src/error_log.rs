@@ -0,0 +1,105 @@
+use std::fmt;
+use std::io;
+use std::sync::{Arc,Mutex};
+use std::net::SocketAddr;
+use std::collections::HashMap;
+
+use clock::{Duration, Instant, SharedClock};
+
+/// What kind of error we ignored
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorCategory {
+	/// a datagram we could not decode or a message without a sender
+	MalformedMessage,
+	/// a message from someone using our own NodeId
+	OwnIdTheft,
+	/// a message in a protocol version we cannot decode
+	IncompatibleVersion,
+	/// a contact that did not fit into the routing table
+	BucketAdd,
+	/// a message of ours we could not encode (e.g. too large for a datagram)
+	Encode,
+	Other,
+}
+
+impl ErrorCategory {
+	/// Category of an error returned by `Kademlia::handle_message()`
+	pub fn of(err: &io::Error) -> ErrorCategory {
+		match err.kind() {
+			io::ErrorKind::InvalidData => ErrorCategory::MalformedMessage,
+			io::ErrorKind::AlreadyExists => ErrorCategory::OwnIdTheft,
+			_ => ErrorCategory::Other,
+		}
+	}
+}
+
+struct Counter {
+	last_warning: Instant,
+	suppressed:   usize,
+}
+
+/// Logs errors we otherwise ignore, at most one warning per category and
+/// `interval` (the rest go to debug and are summarized in the next warning)
+#[derive(Clone)]
+pub struct ErrorLog {
+	counters: Arc<Mutex<HashMap<ErrorCategory, Counter>>>,
+	interval: Duration,
+	clock:    SharedClock,
+}
+
+impl ErrorLog {
+	pub fn new(interval: Duration, clock: SharedClock) -> ErrorLog {
+		ErrorLog {
+			counters: Arc::new(Mutex::new(HashMap::new())),
+			interval: interval,
+			clock:    clock,
+		}
+	}
+
+	/// Returns whether a warning was logged
+	pub fn log<E: fmt::Display>(&self, category: ErrorCategory, src: SocketAddr, err: E) -> bool {
+		debug!("{:?} from {}: {}", category, src, err);
+
+		let now = self.clock.now();
+		let mut counters = self.counters.lock().unwrap();
+
+		if let Some(counter) = counters.get_mut(&category) {
+			if now.duration_since(counter.last_warning) < self.interval {
+				counter.suppressed += 1;
+				return false;
+			}
+		}
+
+		let counter = Counter {
+			last_warning: now,
+			suppressed:   0,
+		};
+		let suppressed = counters.insert(category, counter)
+			.map(|c| c.suppressed)
+			.unwrap_or(0);
+
+		warn!("{:?} from {}: {} ({} more since the last warning)", category, src, err, suppressed);
+		true
+	}
+}
+
+#[cfg(test)]
+use clock::MockClock;
+
+#[test]
+fn rate_limit() {
+	let clock = MockClock::new();
+	let log = ErrorLog::new(Duration::from_secs(60), Arc::new(clock.clone()));
+	let src = "127.0.0.1:2134".parse().unwrap();
+
+	assert!(log.log(ErrorCategory::MalformedMessage, src, "garbage"));
+	for _ in 0..100 {
+		assert!(!log.log(ErrorCategory::MalformedMessage, src, "garbage"));
+	}
+	// categories are limited independently
+	assert!(log.log(ErrorCategory::OwnIdTheft, src, "thief"));
+
+	clock.advance(Duration::from_secs(61));
+	assert!(log.log(ErrorCategory::MalformedMessage, src, "garbage"));
+	assert!(!log.log(ErrorCategory::MalformedMessage, src, "garbage"));
+}
@@ -1,116 +1,496 @@
 use std::io;
+use std::io::Write;
+use std::cmp;
+use std::mem;
+use std::fs::File;
+use std::path::Path;
 use std::sync::mpsc;
-use std::thread::{spawn,sleep};
+use std::thread::spawn;
 use std::net::{UdpSocket,SocketAddr,ToSocketAddrs};
 use std::sync::{Arc,Mutex,RwLock};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
 
 use futures::Future;
 use futures::Stream;
+use futures::sync::oneshot;
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Interval;
 use futures_timer::Sleep;
+use bincode::{serialize, deserialize_from, Bounded};
 
 use storage;
+use codec;
+use chunks;
+use key::Key;
+use trace::{Tracer, LookupTrace, LookupStats};
+use cost::{CostMeter, LookupCost};
+use signing::{self, KeyPair, PublicKey};
+use clock::{self, Duration, Instant, SharedClock};
+use config::{KademliaConfig, ConflictPolicy, EmptyValuePolicy, FarStorePolicy};
 use server::Server;
-use kbuckets::KBuckets;
-use node::{Node, NodeId};
+use kbuckets::{KBuckets, ContactChange};
+use node::{Node, NodeId, Distance, xor};
 use closest_nodes_iter::ClosestNodesIter;
-use message::{Message,Value,Cookie,COOKIE_BYTELEN};
-use message::{Ping,Pong, FindNode, FoundNode, FindValue, FoundValue, Store};
+use message::{self, Message,Value,Cookie,COOKIE_BYTELEN};
+use message::{Ping,Pong, FindNode, FoundNode, FindValue, FoundValue, Store, StoreResponse, Delete};
+use message::{FindValues, FoundValues, MAX_BATCH_KEYS, MAX_BATCH_RESPONSE_LEN, MAX_BATCH_TOTAL_LEN};
+use message::{AccessProof, access_proof_msg, MAX_STORE_HOPS};
+use message::{RecordSignature, record_msg, delete_msg, ttl_ms};
+use message::{Hello, HelloAck, PROTOCOL_VERSION, OWN_FEATURES};
+use utils;
 use utils::ignore;
+use error_log::{ErrorLog, ErrorCategory};
+use utils::thread_limit::ThreadLimit;
+use utils::rate_limit::RateLimiter;
+use utils::worker_pool::WorkerPool;
+use utils::semaphore::Semaphore;
 use message::enc_id;
 
 pub const K_PARAM: usize = 20;
 pub const ALPHA_PARAM: isize = 3;
 pub const TIMEOUT_MS: u32 = 2000;
 pub const MAX_VALUE_LEN: usize = 2048;
+pub const TTL_SECS: u64 = 15*60;
+pub const REFRESH_INTERVAL_SECS: u64 = 60;
+pub const REPUBLISH_INTERVAL_SECS: u64 = 5*60;
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 30;
+/// at most one warning per category of ignored errors in this interval
+pub const ERROR_LOG_INTERVAL_SECS: u64 = 60;
+/// events `routing_events()` receivers did not pick up yet, more are dropped
+pub const ROUTING_EVENT_QUEUE_LEN: usize = 256;
+/// lookups `get_many()` runs at the same time
+pub const MAX_CONCURRENT_LOOKUPS: usize = 16;
+/// upper bound for files written by `save_routing_table()`
+pub const MAX_SNAPSHOT_LEN: u64 = 256*1024;
+
+#[derive(Debug, PartialEq)]
+pub enum PutError {
+	/// The value exceeds `max` bytes
+	TooLarge { max: usize, actual: usize },
+	/// A value with a TTL of zero would expire right away
+	ZeroTtl,
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+	/// Another node uses the NodeId we were pinned to
+	IdTaken(NodeId),
+	/// None of the supernodes answered, try other ones
+	NoSupernodeAnswered { attempts: usize },
+	/// Every NodeId we tried was taken
+	NoFreeId { attempts: usize },
+	/// Binding or querying the socket failed
+	Io(io::Error),
+}
+
+impl From<io::Error> for BootstrapError {
+	fn from(err: io::Error) -> BootstrapError {
+		BootstrapError::Io(err)
+	}
+}
+
+/// Why `try_get()` found no values
+#[derive(Debug, PartialEq)]
+pub enum GetError {
+	/// Enough nodes answered, there is no value for the key
+	NotFound { queried: usize },
+	/// Too few of the nodes we asked answered to be sure
+	Inconclusive { reached: usize },
+}
+
+/// Why `get_large()` could not reassemble a value
+#[derive(Debug, PartialEq)]
+pub enum LargeGetError {
+	/// There is no manifest for the key
+	NotFound,
+	/// Nobody stores chunk number `index` (with the hash in the manifest)
+	MissingChunk { index: usize, key: Key },
+	/// The chunks do not add up to the length in the manifest
+	LengthMismatch { expected: u64, actual: u64 },
+}
+
+/// Result of `get_quorum()`
+#[derive(Debug, PartialEq)]
+pub enum Quorum {
+	/// enough nodes returned this value
+	Reached(Vec<u8>),
+	/// no value had enough support: all distinct values with the number of
+	/// nodes that returned them, best supported first
+	Disputed(Vec<(Vec<u8>, usize)>),
+}
+
+/// What `put_with_mode()` does with the values already stored for a key
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PutMode {
+	/// keep the values of other publishers, `get()` returns all of them.
+	/// Holders keep one value per publisher, so ours is replaced anyway.
+	Append,
+	/// remove the ones we stored (on a best effort basis: holders we do
+	/// not reach keep them), values of other publishers stay
+	Replace,
+}
+
+/// What a peer told us in its `HelloAck`
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerCapabilities {
+	pub version:          String,
+	pub protocol_version: u8,
+	/// `message::FEATURE_*` flags
+	pub features:         u32,
+}
+
+impl PeerCapabilities {
+	pub fn supports(&self, feature: u32) -> bool {
+		self.features & feature == feature
+	}
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RoutingEventKind {
+	/// a new contact entered the routing table
+	Added,
+	/// a contact was replaced or removed because it did not answer
+	Evicted,
+	/// a contact did not fit into its (full) bucket
+	Rejected,
+}
+
+/// Routing table churn, see `routing_events()`
+#[derive(Debug, Clone)]
+pub struct RoutingEvent {
+	pub kind: RoutingEventKind,
+	pub node: Node,
+	pub at:   Instant,
+}
+
+/// Decides whether to accept a signed value, see `create_with_verifier()`
+pub type RecordVerifier = Arc<Fn(&Key, &[u8], &RecordSignature) -> bool + Send + Sync>;
+
+/// Accepts values with a valid ed25519 signature by any key
+pub fn verify_signature(key: &Key, value: &[u8], signature: &RecordSignature) -> bool {
+	let msg = record_msg(&key.id(), value);
+	signing::verify(&msg[..], &signature.public_key, &signature.signature[..])
+}
+
+/// State changes of a peer registered with `watch_peer()`
+#[derive(Debug, PartialEq, Clone)]
+pub enum PeerEvent {
+	Up(SocketAddr),
+	Down(SocketAddr),
+}
 
 #[derive(Clone)]
 pub struct Kademlia {
 	own_id: Arc<Mutex<NodeId>>,
-	stored_values: Arc<RwLock<HashMap<NodeId, (u64, Vec<u8>)>>>,
+	/// values we republish until they expire, see `put_with_ttl()`
+	stored_values: Arc<RwLock<HashMap<(Key, Vec<u8>), Instant>>>,
 	server: Server,
 	kbuckets: KBuckets,
 	external_values: storage::ExternalStorage,
 	listeners: storage::ExternalStorage,
-	ttl: Duration,
+	config: KademliaConfig,
+	clock: SharedClock,
+	verifier: RecordVerifier,
+	bootstrapped: Arc<AtomicBool>,
+	paused: Arc<AtomicBool>,
+	dropped_while_paused: Arc<AtomicUsize>,
+	dropped_overload: Arc<AtomicUsize>,
+	rate_limiter: RateLimiter,
+	dropped_rate_limited: Arc<AtomicUsize>,
+	threads: ThreadLimit,
+	errors: ErrorLog,
+	last_lookup: Arc<Mutex<Option<Instant>>>,
+	routing_listeners: Arc<Mutex<Vec<mpsc::SyncSender<RoutingEvent>>>>,
+	/// signalled when the receive loop exits, see `shutdown()`
+	receiver_done: Arc<Mutex<Option<mpsc::Receiver<()>>>>,
+	/// ends the periodic tasks right away and tells when they are gone, see `spawn_task()`
+	tasks: Arc<Mutex<Vec<(oneshot::Sender<()>, mpsc::Receiver<()>)>>>,
 }
 
 impl Kademlia {
 	#[allow(dead_code)]
-	pub fn new_supernode<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>) -> Kademlia {
+	pub fn new_supernode<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>)
+		-> io::Result<Kademlia>
+	{
 		let own_id = own_id.or_else(|| Some(Node::generate_id()));
 		Self::create(handle, addr, own_id)
 	}
 
-	pub fn create<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>) -> Kademlia {
-		let udp = UdpSocket::bind(addr).unwrap();
-		let server = Server::new(handle, udp);
+	pub fn create<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>)
+		-> io::Result<Kademlia>
+	{
+		Self::create_with_config(handle, addr, own_id, KademliaConfig::default())
+	}
 
-		let ttl = Duration::from_secs(15*60);
+	pub fn create_with_config<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>,
+		config: KademliaConfig) -> io::Result<Kademlia>
+	{
+		let udp = try!(utils::bind_udp(addr));
+		Self::with_socket(handle, udp, own_id, config)
+	}
+
+	/// Like `create_with_config()`, but signed values (in Stores and in
+	/// lookup results) are only accepted if `verifier` returns true.
+	/// The default is `verify_signature()`; a verifier that only trusts
+	/// certain publishers should call it, too.
+	pub fn create_with_verifier<A: ToSocketAddrs>(handle: Handle, addr: A, own_id: Option<NodeId>,
+		config: KademliaConfig, verifier: RecordVerifier) -> io::Result<Kademlia>
+	{
+		let udp = try!(utils::bind_udp(addr));
+		Self::with_socket_and_verifier(handle, udp, own_id, config, verifier)
+	}
+
+	/// Like `create()`, but starts with the contacts saved by
+	/// `save_routing_table()`. They are not trusted more than any other
+	/// contact: those that do not answer anymore get replaced as usual.
+	pub fn create_from_snapshot<A,P>(handle: Handle, addr: A, own_id: Option<NodeId>, path: P)
+		-> io::Result<Kademlia>
+		where A: ToSocketAddrs, P: AsRef<Path>
+	{
+		Self::create_from_snapshot_with_config(handle, addr, own_id, path, KademliaConfig::default())
+	}
+
+	pub fn create_from_snapshot_with_config<A,P>(handle: Handle, addr: A, own_id: Option<NodeId>,
+		path: P, config: KademliaConfig) -> io::Result<Kademlia>
+		where A: ToSocketAddrs, P: AsRef<Path>
+	{
+		// a corrupt (or forged) length prefix must not make us allocate gigabytes
+		let mut file = try!(File::open(path));
+		let nodes:Vec<Node> = try!(deserialize_from(&mut file, Bounded(MAX_SNAPSHOT_LEN))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+		let mut kad = try!(Self::create_with_config(handle, addr, own_id, config));
+		debug!("Loaded {} contacts from the snapshot", nodes.len());
+
+		for node in nodes.into_iter() {
+			// same checks as for any other new contact
+			match Node::with_clock(node.addr, node.node_id, kad.clock.clone()) {
+				Ok(node) => kad.add_contact(node),
+				Err(e) => debug!("Ignoring saved contact {}: {}", node.addr, e),
+			}
+		}
+
+		Ok(kad)
+	}
+
+	/// Like `create_with_config()`, but uses an already bound socket,
+	/// e.g. with platform specific options (IP_TOS, ...) set
+	pub fn with_socket(handle: Handle, udp: UdpSocket, own_id: Option<NodeId>,
+		config: KademliaConfig) -> io::Result<Kademlia>
+	{
+		Self::with_socket_and_verifier(handle, udp, own_id, config, Arc::new(verify_signature))
+	}
+
+	fn with_socket_and_verifier(handle: Handle, udp: UdpSocket, own_id: Option<NodeId>,
+		config: KademliaConfig, verifier: RecordVerifier) -> io::Result<Kademlia>
+	{
+		try!(config.validate());
+
+		let clock = clock::system();
+		let errors = ErrorLog::new(Duration::from_secs(ERROR_LOG_INTERVAL_SECS), clock.clone());
+
+		let server = try!(Server::new(handle, udp, codec::for_format(config.wire_format),
+			errors.clone(), clock.clone()));
+
+		debug!("{:?}", config);
+
+		let ttl = config.ttl;
+		let threads = ThreadLimit::new(config.max_threads);
+		let rate_limiter = RateLimiter::new(config.msg_rate_per_source,
+			config.msg_burst_per_source, clock.clone());
 		let own_id = own_id.unwrap_or_else(|| Node::generate_id());
 		let own_id = Arc::new(Mutex::new(own_id));
+		let (done_tx, done_rx) = mpsc::channel();
 
 		let kad = Kademlia {
 			own_id:          own_id.clone(),
 			server:          server.clone(),
 			stored_values:   Arc::new(RwLock::new(HashMap::new())),
-			kbuckets:        KBuckets::new(own_id),
-			external_values: storage::ExternalStorage::new(ttl),
-			listeners:       storage::ExternalStorage::new(ttl),
-			ttl:             ttl,
+			kbuckets:        KBuckets::with_address_policy(own_id, config.k, config.address_policy,
+				Some(server.local_addr)),
+			external_values: storage::ExternalStorage::with_clock(ttl, clock.clone()),
+			listeners:       storage::ExternalStorage::with_clock(ttl, clock.clone()),
+			config:          config,
+			clock:           clock,
+			verifier:        verifier,
+			bootstrapped:    Arc::new(AtomicBool::new(false)),
+			paused:          Arc::new(AtomicBool::new(false)),
+			dropped_while_paused: Arc::new(AtomicUsize::new(0)),
+			dropped_overload: Arc::new(AtomicUsize::new(0)),
+			rate_limiter:    rate_limiter,
+			dropped_rate_limited: Arc::new(AtomicUsize::new(0)),
+			threads:         threads,
+			errors:          errors,
+			last_lookup:     Arc::new(Mutex::new(None)),
+			routing_listeners: Arc::new(Mutex::new(vec![])),
+			receiver_done:   Arc::new(Mutex::new(Some(done_rx))),
+			tasks:           Arc::new(Mutex::new(vec![])),
 		};
 
+		let worker = kad.clone();
+		let pool = try!(WorkerPool::new(kad.config.handler_threads, kad.config.max_pending_messages,
+			&kad.threads, move |(src, version, msg): (SocketAddr, u8, Message)| {
+				let mut this = worker.clone();
+				if let Err(e) = this.handle_message(src, version, msg) {
+					this.errors.log(ErrorCategory::of(&e), src, e);
+				}
+			}));
+
 		let this = kad.clone();
 		let handle = this.server.handle.clone();
 		handle.spawn_fn(move || {
-			for (src, msg) in server {
-				let mut this = this.clone();
+			for (src, version, msg) in server {
+				if this.paused.load(Ordering::SeqCst) {
+					// responses to our own requests were already dispatched by the server
+					this.dropped_while_paused.fetch_add(1, Ordering::SeqCst);
+					continue;
+				}
 
-				handle.spawn_fn(move || {
-					ignore(this.handle_message(src, msg));
-					Ok(())
-				});
+				if !this.rate_limiter.allow(src.ip()) {
+					let dropped = this.dropped_rate_limited.fetch_add(1, Ordering::SeqCst) + 1;
+					debug!("Rate limit exceeded, dropped message from {} ({} so far)", src, dropped);
+					continue;
+				}
+
+				/*
+				 * Don't let handlers pile up under overload: drop the datagram
+				 * (like the kernel would if we stopped reading the socket),
+				 * but keep on reading.
+				 */
+				if pool.try_submit((src, version, msg)).is_err() {
+					let dropped = this.dropped_overload.fetch_add(1, Ordering::SeqCst) + 1;
+					debug!("Overloaded, dropped message from {} ({} so far)", src, dropped);
+				}
 			}
+			// the workers stop once they handled what is queued
+			pool.join();
+			drop(this);
+			debug!("Receive loop stopped");
+			ignore(done_tx.send(()));
 			Ok(())
 		});
 
-		let this = kad.clone();
+		let mut this = kad.clone();
 		let handle = this.server.handle.clone();
-		handle.spawn(Interval::new(Duration::from_secs(60), &handle).unwrap().for_each(move |_| {
-			let node_id = Node::generate_id();
-			this.find_node(node_id);
+		let refresh_interval = Duration::from_secs(REFRESH_INTERVAL_SECS);
+		kad.spawn_task("Refresh", try!(Interval::new(refresh_interval, &handle)).for_each(move |_| {
+			try!(this.check_running());
+			this.kbuckets.resolve_hostnames();
+			this.evict_stale_contacts();
+
+			// real lookups keep the routing table fresh anyway
+			if !this.is_idle(refresh_interval) {
+				debug!("Skipping bucket refresh, the node is busy");
+				return Ok(());
+			}
+
+			// lookups refresh the bucket of their key, see KBuckets::touch()
+			let now = this.clock.now();
+			for idx in this.kbuckets.stale_buckets(now, refresh_interval) {
+				debug!("Refreshing bucket {}", idx);
+				let node_id = this.kbuckets.random_id_in_bucket(idx);
+				this.find_node(node_id);
+			}
 			Ok(()) as Result<(), io::Error>
-		}).map_err(|_| ()));
+		}));
 
 		let mut this = kad.clone();
-		handle.spawn(Interval::new(Duration::from_secs(5*60), &handle).unwrap().for_each(move |_| {
-			// publish stored values again and again
-			let stored_values = this.stored_values.clone();
-			let mut store = stored_values.write().unwrap();
+		let republish_interval = Duration::from_secs(REPUBLISH_INTERVAL_SECS);
+		kad.spawn_task("Republish", try!(Interval::new(republish_interval, &handle)).for_each(move |_| {
+			try!(this.check_running());
 
-			for (key, t) in store.iter_mut() {
-				let (ref mut lifetime, ref value) = *t;
-				*lifetime = lifetime.saturating_sub(5 * 60);
-
-				if *lifetime > 0 {
-					this.put(*key, value.clone()).unwrap();
+			// publish stored values again and again
+			let now = this.clock.now();
+			let stored:Vec<((Key, Vec<u8>), Instant)> = {
+				let mut store = this.stored_values.write().unwrap();
+				store.retain(|_, &mut expires| expires > now);
+				store.iter().map(|(entry, &expires)| (entry.clone(), expires)).collect()
+			};
+
+			// keep the expiry: holders drop the value when we would
+			let timeout = Duration::from_millis(this.config.timeout_ms as u64);
+			for ((key, value), expires) in stored {
+				let remaining = expires.duration_since(now);
+				if remaining < timeout {
+					debug!("Not republishing {}, it is about to expire", key);
+					continue;
 				}
+				// removed while we were publishing the others?
+				if !this.stored_values.read().unwrap().contains_key(&(key, value.clone())) {
+					continue;
+				}
+				this.publish(key, value, None, PutMode::Append, remaining, None);
 			}
 
+			this.refresh_expiring_values(republish_interval);
+
 			Ok(()) as Result<(), io::Error>
-		}).map_err(|_| ()));
+		}));
 
-		kad
+		Ok(kad)
 	}
 
 	pub fn bootstrap<A,B>(handle: Handle, addr: A, supernodes: Vec<B>, new_id: Option<NodeId>)
-		-> Kademlia
+		-> Result<Kademlia, BootstrapError>
+		where A: ToSocketAddrs, B: ToSocketAddrs
+	{
+		Self::bootstrap_with_config(handle, addr, supernodes, new_id, KademliaConfig::default())
+	}
+
+	pub fn bootstrap_with_config<A,B>(handle: Handle, addr: A, supernodes: Vec<B>,
+		new_id: Option<NodeId>, config: KademliaConfig) -> Result<Kademlia, BootstrapError>
+		where A: ToSocketAddrs, B: ToSocketAddrs
+	{
+		let attempts = config.max_bootstrap_attempts;
+		let mut kad = try!(Self::with_supernodes(handle, addr, supernodes, config));
+
+		let mut new_id = new_id.unwrap_or_else(|| Node::generate_id());
+		let mut answered = false;
+		for attempt in 0..attempts {
+			match kad.join(new_id) {
+				Ok(()) => return Ok(kad),
+				Err(BootstrapError::IdTaken(_)) => {
+					answered = true;
+					new_id = Node::generate_id();
+				},
+				Err(BootstrapError::NoSupernodeAnswered { .. }) =>
+					debug!("Bootstrap attempt {} got no answer", attempt + 1),
+				Err(e) => return Err(e),
+			}
+		}
+
+		if answered {
+			Err(BootstrapError::NoFreeId { attempts: attempts })
+		} else {
+			Err(BootstrapError::NoSupernodeAnswered { attempts: attempts })
+		}
+	}
+
+	/// Like `bootstrap()`, but fails if another node uses `id` already
+	/// (instead of picking a new, random NodeId)
+	pub fn bootstrap_pinned<A,B>(handle: Handle, addr: A, supernodes: Vec<B>, id: NodeId)
+		-> Result<Kademlia, BootstrapError>
+		where A: ToSocketAddrs, B: ToSocketAddrs
+	{
+		Self::bootstrap_pinned_with_config(handle, addr, supernodes, id, KademliaConfig::default())
+	}
+
+	pub fn bootstrap_pinned_with_config<A,B>(handle: Handle, addr: A, supernodes: Vec<B>, id: NodeId,
+		config: KademliaConfig) -> Result<Kademlia, BootstrapError>
+		where A: ToSocketAddrs, B: ToSocketAddrs
+	{
+		let mut kad = try!(Self::with_supernodes(handle, addr, supernodes, config));
+		try!(kad.join(id));
+
+		Ok(kad)
+	}
+
+	fn with_supernodes<A,B>(handle: Handle, addr: A, supernodes: Vec<B>, config: KademliaConfig)
+		-> io::Result<Kademlia>
 		where A: ToSocketAddrs, B: ToSocketAddrs
 	{
-		let mut kad = Self::create(handle, addr, None);
+		let mut kad = try!(Self::create_with_config(handle, addr, None, config));
 
 		for address in supernodes.into_iter() {
 			/*
@@ -119,50 +499,453 @@ impl Kademlia {
 			 */
 
 			let node_id = Node::generate_id();
-			let node = Node::new(address, node_id);
+			match Node::with_clock(address, node_id, kad.clock.clone()) {
+				Ok(ref node) if !kad.kbuckets.accepts(&node.addr) =>
+					warn!("Ignoring supernode {}: the address policy does not accept it", node.addr),
+				Ok(node) => kad.add_contact(node),
+				Err(e) => warn!("Ignoring supernode: {}", e),
+			}
+		}
+
+		Ok(kad)
+	}
 
-			ignore(node.map(|n| kad.kbuckets.add(n)));
+	/// Look ourselves up with NodeId `id` and fill the routing table
+	/// with the result, unless some other node uses `id` already
+	fn join(&mut self, id: NodeId) -> Result<(), BootstrapError> {
+		self.set_own_id(id);
+
+		let node_list = self.find_node(id);
+
+		if node_list.is_empty() {
+			return Err(BootstrapError::NoSupernodeAnswered { attempts: 1 });
 		}
 
-		let mut new_id = new_id.unwrap_or_else(|| Node::generate_id());
-		loop {
-			kad.set_own_id(new_id);
+		// others may see us under any of our addresses
+		if node_list.iter().any(|n| n.node_id == id && !self.kbuckets.is_us(&n.addr)) {
+			warn!("NodeId {} is taken already", enc_id(&id));
+			return Err(BootstrapError::IdTaken(id));
+		}
+
+		self.bootstrapped.store(true, Ordering::SeqCst);
+		for n in node_list.into_iter() {
+			self.add_contact(n);
+		}
+
+		Ok(())
+	}
+
+	/// Add a supernode by hostname that is re-resolved periodically,
+	/// so it stays reachable even if its IP address changes.
+	pub fn add_supernode(&mut self, hostname: &str) -> io::Result<()> {
+		// see bootstrap() why the NodeId does not matter
+		let node = try!(Node::with_hostname(hostname, Node::generate_id()));
+		self.add_contact(node);
+		Ok(())
+	}
+
+	fn add_contact(&mut self, node: Node) {
+		let is_new = !self.is_contact(&node);
 
-			let node_list = kad.find_node(new_id);
+		match self.kbuckets.add(node.clone()) {
+			Ok(()) if is_new && self.is_contact(&node) => self.notify(RoutingEventKind::Added, node),
+			Ok(()) => (),
+			Err(node) => {
+				self.errors.log(ErrorCategory::BucketAdd, node.addr, "bucket is full");
+				self.notify(RoutingEventKind::Rejected, node);
+			},
+		}
+	}
+
+	fn is_contact(&self, node: &Node) -> bool {
+		self.kbuckets.get_bucket(&node.node_id)
+			.map(|b| b.contains(node))
+			.unwrap_or(false)
+	}
+
+	/// Get notified of routing table churn. Events are dropped while
+	/// `ROUTING_EVENT_QUEUE_LEN` of them wait in the receiver.
+	pub fn routing_events(&self) -> mpsc::Receiver<RoutingEvent> {
+		let (tx, rx) = mpsc::sync_channel(ROUTING_EVENT_QUEUE_LEN);
+		self.routing_listeners.lock().unwrap().push(tx);
+		rx
+	}
+
+	fn notify(&self, kind: RoutingEventKind, node: Node) {
+		let event = RoutingEvent {
+			kind: kind,
+			node: node,
+			at:   self.clock.now(),
+		};
 
-			if !node_list.iter().any(|n|
-					n.node_id == new_id &&
-					n.addr != kad.server.local_addr().unwrap() //TODO: unwrap!?
-				) {
+		let mut listeners = self.routing_listeners.lock().unwrap();
+		listeners.retain(|tx| match tx.try_send(event.clone()) {
+			Err(mpsc::TrySendError::Disconnected(_)) => false,
+			Err(mpsc::TrySendError::Full(_)) => {
+				debug!("Dropping {:?}, the receiver is slow", event.kind);
+				true
+			},
+			Ok(()) => true,
+		});
+	}
 
-				for n in node_list.into_iter() {
-					ignore(kad.kbuckets.add(n));
+	/// Ping `addr` periodically (regardless of the routing table) and report
+	/// whenever it goes up or down. The first ping always yields an event.
+	/// Watching stops at the next state change after the receiver was dropped.
+	pub fn watch_peer<A: ToSocketAddrs>(&self, addr: A) -> io::Result<mpsc::Receiver<PeerEvent>> {
+		// the NodeId does not matter for a Ping
+		let node = try!(Node::with_clock(addr, Node::generate_id(), self.clock.clone()));
+		let guard = try!(self.threads.try_acquire()
+			.ok_or(io::Error::new(io::ErrorKind::Other, "Thread limit reached")));
+		let (tx, rx) = mpsc::channel();
+
+		let this = self.clone();
+		let handle = self.server.handle.clone();
+		let interval = Duration::from_secs(HEARTBEAT_INTERVAL_SECS);
+		let mut is_up = None;
+
+		self.spawn_task("Watching a peer", try!(Interval::new(interval, &handle)).for_each(move |_| {
+			// released when watching stops
+			let _ = &guard;
+			try!(this.check_running());
+			let up = this.ping(&node);
+
+			if is_up != Some(up) {
+				is_up = Some(up);
+
+				let event = if up { PeerEvent::Up(node.addr) } else { PeerEvent::Down(node.addr) };
+				debug!("{:?}", event);
+
+				if tx.send(event).is_err() {
+					return Err(io::Error::new(io::ErrorKind::Other, "Nobody is watching anymore"));
 				}
+			}
 
-				break;
+			Ok(())
+		}));
+
+		Ok(rx)
+	}
+
+	/// Runs `task` on the reactor until it ends or `shutdown()` is called,
+	/// whichever comes first, so it does not keep the socket open until
+	/// its next tick
+	fn spawn_task<F>(&self, name: &'static str, task: F)
+		where F: Future<Item=(), Error=io::Error> + 'static
+	{
+		let (stop_tx, stop_rx) = oneshot::channel();
+		let (done_tx, done_rx) = mpsc::channel();
+
+		{
+			let mut tasks = self.tasks.lock().unwrap();
+			// forget the tasks that already ended
+			tasks.retain(|&(ref stop, _)| !stop.is_canceled());
+			tasks.push((stop_tx, done_rx));
+		}
+
+		self.server.handle.spawn(task.select2(stop_rx).then(move |res| {
+			// drops the task (and the clone of us it holds) before we report it gone
+			drop(res);
+			debug!("{} task stopped", name);
+			ignore(done_tx.send(()));
+			Ok(())
+		}));
+	}
+
+	/// Stop the receive loop and all periodic tasks (refresh, republish,
+	/// `watch_peer()`) of this node and all its clones, and wait (at most
+	/// `timeout_ms` each) for them to exit. The socket is closed once the
+	/// last clone is dropped, i.e. right away unless the caller keeps one.
+	pub fn shutdown(self) {
+		info!("Shutting down");
+		self.server.stop();
+
+		let tasks = mem::replace(&mut *self.tasks.lock().unwrap(), vec![]);
+		let mut done:Vec<mpsc::Receiver<()>> = tasks.into_iter().map(|(stop, done)| {
+			ignore(stop.send(()));
+			done
+		}).collect();
+		done.extend(self.receiver_done.lock().unwrap().take());
+
+		let timeout = Duration::from_millis(self.config.timeout_ms as u64);
+		for done in done {
+			if done.recv_timeout(timeout).is_err() {
+				warn!("A task did not stop within {:?}", timeout);
 			}
+		}
+	}
 
-			new_id = Node::generate_id();
+	/// Err after `shutdown()`, ends the periodic tasks
+	fn check_running(&self) -> io::Result<()> {
+		if self.server.is_stopped() {
+			Err(io::Error::new(io::ErrorKind::Other, "Shut down"))
+		} else {
+			Ok(())
 		}
+	}
 
-		kad
+	/// Number of message handler threads, peer watchers and `get_many()`
+	/// workers currently running
+	pub fn thread_count(&self) -> usize {
+		self.threads.active()
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.server.local_addr()
 	}
 
 	pub fn get_nodes(&self) -> Vec<Node> {
 		self.kbuckets.get_nodes()
 	}
 
-	pub fn get(&self, key: NodeId) -> Vec<Vec<u8>> {
-		debug!("Finding {}...", enc_id(&key));
-        let values:Vec<Vec<u8>> = self.find_value(key).iter().collect();
+	/// Save our contacts (address and NodeId) for `create_from_snapshot()`
+	pub fn save_routing_table<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		let contents = try!(serialize(&self.get_nodes(), Bounded(MAX_SNAPSHOT_LEN))
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+
+		let mut file = try!(File::create(path));
+		file.write_all(&contents[..])
+	}
+
+	/// Are we connected to the network?
+	///
+	/// True if bootstrapping found other nodes and we still know at least
+	/// one of them.
+	pub fn is_bootstrapped(&self) -> bool {
+		self.bootstrapped.load(Ordering::SeqCst) && !self.kbuckets.is_empty()
+	}
+
+	pub fn get(&self, key: Key) -> Vec<Vec<u8>> {
+		debug!("Finding {}...", key);
+		self.note_lookup();
+        let values:Vec<Vec<u8>> = self.find_value(key, None, None, self.cost_meter(), None).iter().collect();
 		if values.len() > 0 {
-			info!("Found {:?} values for {}", values.len(), enc_id(&key));
+			info!("Found {:?} values for {}", values.len(), key);
 		} else {
-			warn!("Found NO values for {}", enc_id(&key));
+			warn!("Found NO values for {}", key);
 		}
 		values
 	}
 
+	/// Like `get()`, but tells apart whether there is no value for `key`
+	/// or we could not reach enough nodes to tell
+	pub fn try_get(&self, key: Key) -> Result<Vec<Vec<u8>>, GetError> {
+		self.note_lookup();
+
+		let meter = self.cost_meter();
+		let values:Vec<Vec<u8>> = self.find_value(key, None, None, meter.clone(), None).iter().collect();
+		if !values.is_empty() {
+			return Ok(values);
+		}
+
+		let cost = meter.cost();
+		// less than half of the nodes answered
+		if cost.nodes_reached == 0 || 2*cost.nodes_reached < cost.queries {
+			Err(GetError::Inconclusive { reached: cost.nodes_reached })
+		} else {
+			Err(GetError::NotFound { queried: cost.queries })
+		}
+	}
+
+	/// Like `get()`, but also returns values with an ACL that contains
+	/// `keypair`'s public key
+	pub fn get_authorized(&self, key: Key, keypair: &KeyPair) -> Vec<Vec<u8>> {
+		self.note_lookup();
+		self.find_value(key, None, Some(keypair.clone()), self.cost_meter(), None).iter().collect()
+	}
+
+	/// Like `get()`, but also records every request and response of the lookup
+	pub fn get_traced(&self, key: Key) -> (Vec<Vec<u8>>, LookupTrace) {
+		self.note_lookup();
+
+		let tracer = Tracer::new(key.id(), self.clock.clone());
+        let values:Vec<Vec<u8>> = self.find_value(key, Some(tracer.clone()), None, self.cost_meter(), None).iter().collect();
+		let trace = tracer.finish(values.clone());
+
+		(values, trace)
+	}
+
+	/// Ask the nodes closest to `key` one by one until `n` of them returned
+	/// the same value
+	pub fn get_quorum(&self, key: Key, n: usize) -> Quorum {
+		self.note_lookup();
+
+		let mut support:HashMap<Vec<u8>, usize> = HashMap::new();
+		for node in self.find_node(key.id()) {
+			let values:HashSet<Vec<u8>> = self.find_value_at(&node, key).into_iter().collect();
+
+			for v in values {
+				let count = support.entry(v.clone()).or_insert(0);
+				*count += 1;
+
+				if *count >= n {
+					debug!("{} nodes agree on a value for {}", count, key);
+					return Quorum::Reached(v);
+				}
+			}
+		}
+
+		let mut values:Vec<(Vec<u8>, usize)> = support.into_iter().collect();
+		values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+		info!("No quorum of {} for {} ({} distinct values)", n, key, values.len());
+
+		Quorum::Disputed(values)
+	}
+
+	/// Like `get()`, but also reports how many nodes the lookup queried
+	/// and how many hops it took, see `LookupStats`
+	pub fn find_with_stats(&self, key: Key) -> (Vec<Vec<u8>>, LookupStats) {
+		let (values, trace) = self.get_traced(key);
+		(values, trace.stats())
+	}
+
+	/// Like `get()`, but stops once the lookup caused more than `max_bytes`
+	/// of traffic (if given) and reports how much it caused
+	pub fn get_with_cost(&self, key: Key, max_bytes: Option<usize>) -> (Vec<Vec<u8>>, LookupCost) {
+		self.note_lookup();
+
+		let meter = CostMeter::new(max_bytes);
+		let values:Vec<Vec<u8>> = self.find_value(key, None, None, meter.clone(), None).iter().collect();
+
+		(values, meter.cost())
+	}
+
+	/// Look up several keys at once. The lookups run concurrently, but
+	/// share a budget of `config.alpha` queries in flight, and their threads
+	/// count towards `config.max_threads` (one by one if it is reached).
+	/// Keys without values (or whose lookup failed) map to an empty Vec.
+	pub fn get_many(&self, keys: Vec<Key>) -> HashMap<Key, Vec<Vec<u8>>> {
+		let mut result:HashMap<Key, Vec<Vec<u8>>> = keys.iter()
+			.map(|k| (*k, vec![]))
+			.collect();
+		if keys.is_empty() {
+			return result;
+		}
+
+		let budget = Arc::new(Semaphore::new(self.config.alpha));
+		let (tx, rx) = mpsc::channel();
+		let tx = Mutex::new(tx);
+		let this = self.clone();
+
+		let threads = cmp::min(keys.len(), MAX_CONCURRENT_LOOKUPS);
+		let pool = WorkerPool::new(threads, keys.len(), &self.threads, move |key: Key| {
+			let values:Vec<Vec<u8>> = this.find_value(key, None, None, this.cost_meter(),
+				Some(budget.clone())).iter().collect();
+			ignore(tx.lock().unwrap().send((key, values)));
+		});
+		let pool = match pool {
+			Ok(pool) => pool,
+			Err(e) => {
+				debug!("{}, looking up {} keys one by one", e, keys.len());
+				for key in keys.into_iter() {
+					result.insert(key, self.get(key));
+				}
+				return result;
+			},
+		};
+		for key in keys.into_iter() {
+			ignore(pool.try_submit(key));
+		}
+		// the workers stop once all keys are looked up
+		drop(pool);
+
+		for (key, values) in rx.iter() {
+			result.insert(key, values);
+		}
+		result
+	}
+
+	/// Extend the values other nodes stored here that would expire within
+	/// `period`, if the other holders of the key still have them.
+	/// See `KademliaConfig::max_holder_refreshes`.
+	fn refresh_expiring_values(&self, period: Duration) {
+		let max_refreshes = self.config.max_holder_refreshes;
+		if max_refreshes == 0 {
+			return;
+		}
+
+		let mut external_values = self.external_values.clone();
+		let expiring:Vec<(NodeId, storage::Entry)> = external_values.expiring(period).into_iter()
+			.filter(|&(_, ref e)| e.acl.is_none() && e.holder_refreshes < max_refreshes)
+			.collect();
+
+		let mut found:HashMap<NodeId, Vec<Vec<u8>>> = HashMap::new();
+		for (key, entry) in expiring {
+			if !found.contains_key(&key) {
+				// our own values are not part of the result
+				let values = self.find_value(Key::new(key), None, None, self.cost_meter(), None).iter().collect();
+				found.insert(key, values);
+			}
+
+			if found[&key].contains(&entry.value) {
+				debug!("Other holders still have a value for {}, extending it", enc_id(&key));
+				external_values.extend(&key, &entry.value[..]);
+			}
+		}
+	}
+
+	fn cost_meter(&self) -> CostMeter {
+		CostMeter::new(self.config.max_lookup_bytes)
+	}
+
+	/// Values other nodes stored on this node for `key`, along with the
+	/// NodeId of the node that published each of them
+	pub fn cached_value_publishers(&self, key: &Key) -> Vec<(Vec<u8>, NodeId)> {
+		let mut external_values = self.external_values.clone();
+
+		external_values.get(&key.id()).into_iter()
+			.map(|((_, publisher), value)| (value, publisher))
+			.collect()
+	}
+
+	/// Number of incoming messages dropped because too many were pending
+	pub fn dropped_overload(&self) -> usize {
+		self.dropped_overload.load(Ordering::SeqCst)
+	}
+
+	/// Number of incoming messages dropped because their source exceeded
+	/// `config.msg_rate_per_source`
+	pub fn dropped_rate_limited(&self) -> usize {
+		self.dropped_rate_limited.load(Ordering::SeqCst)
+	}
+
+	/// Changes of the routing table after sequence number `seq`
+	/// (see `KBuckets::changes_since()`), so monitors need not poll
+	/// `get_nodes()`
+	pub fn table_changes_since(&self, seq: u64) -> (Vec<ContactChange>, u64) {
+		self.kbuckets.changes_since(seq)
+	}
+
+	/// Contacts that send us requests, but never answered ours
+	pub fn inbound_only_contacts(&self) -> Vec<Node> {
+		self.kbuckets.get_nodes().into_iter()
+			.filter(|n| n.is_inbound_only())
+			.collect()
+	}
+
+	/// Smoothed round trip time of a contact in our routing table
+	pub fn contact_rtt(&self, node_id: &NodeId) -> Option<Duration> {
+		self.kbuckets.get_bucket(node_id)
+			.and_then(|b| b.iter().find(|n| n.node_id == *node_id).and_then(|n| n.rtt()))
+	}
+
+	/// Maintenance mode: while paused, incoming messages are dropped
+	/// (without touching the routing table or storage).
+	pub fn set_paused(&self, paused: bool) {
+		info!("Message handling {}", if paused { "paused" } else { "resumed" });
+		self.paused.store(paused, Ordering::SeqCst);
+	}
+
+	/// Number of incoming messages dropped while paused
+	pub fn dropped_while_paused(&self) -> usize {
+		self.dropped_while_paused.load(Ordering::SeqCst)
+	}
+
+	/// The configuration in effect (including defaults)
+	pub fn config(&self) -> KademliaConfig {
+		self.config.clone()
+	}
+
 	pub fn get_own_id(&self) -> NodeId {
 		self.own_id.lock().unwrap().clone()
 	}
@@ -172,19 +955,217 @@ impl Kademlia {
 		*own_id = new_id;
 	}
 
-    /// Just store a value once
+    /// Just store a value once.
+    /// Returns how many nodes confirmed that they stored it.
     #[async]
-	pub fn put(&mut self, key: NodeId, value: Vec<u8>) -> Result<(),Vec<u8>> {
-		if value.len() > MAX_VALUE_LEN {
-			return Err(value);
+	pub fn put(&mut self, key: Key, value: Vec<u8>) -> Result<usize,PutError> {
+		self.put_with_mode(key, value, PutMode::Append)
+	}
+
+	/// Like `put()`, but `PutMode::Replace` removes the values we stored
+	/// for `key` before
+	pub fn put_with_mode(&mut self, key: Key, value: Vec<u8>, mode: PutMode) -> Result<usize,PutError> {
+		if value.len() > self.config.max_value_len && self.config.auto_chunk {
+			return self.put_large_with_mode(key, value, mode);
+		}
+		try!(self.check_len(value.len()));
+
+		Ok(self.publish(key, value, None, mode, self.config.ttl, None))
+	}
+
+	/// `PutError::TooLarge` if a value of `len` bytes exceeds `config.max_value_len`
+	fn check_len(&self, len: usize) -> Result<(),PutError> {
+		if len > self.config.max_value_len {
+			return Err(PutError::TooLarge {
+				max:    self.config.max_value_len,
+				actual: len,
+			});
+		}
+		Ok(())
+	}
+
+	/// Like `put()`, but the value expires after `ttl` (instead of
+	/// `config.ttl`). We republish it until then.
+	pub fn put_with_ttl(&mut self, key: Key, value: Vec<u8>, ttl: Duration) -> Result<usize,PutError> {
+		try!(self.check_len(value.len()));
+		if ttl == Duration::from_secs(0) {
+			return Err(PutError::ZeroTtl);
+		}
+
+		// putting it again only updates the expiry, it is republished once
+		let expires = self.clock.now() + ttl;
+		self.stored_values.write().unwrap().insert((key, value.clone()), expires);
+
+		Ok(self.publish(key, value, None, PutMode::Append, ttl, None))
+	}
+
+	/// Time until the last value stored for `key` with `put_with_ttl()` expires
+	pub fn remaining_ttl(&self, key: &Key) -> Option<Duration> {
+		let now = self.clock.now();
+
+		self.stored_values.read().unwrap().iter()
+			.filter(|&(&(ref k, _), &expires)| k == key && expires > now)
+			.map(|(_, &expires)| expires.duration_since(now))
+			.max()
+	}
+
+	/// Stop republishing `value` (other nodes keep it until it expires)
+	pub fn remove(&self, key: &Key, value: &[u8]) {
+		self.stored_values.write().unwrap().remove(&(*key, value.to_vec()));
+	}
+
+	/// Stop republishing all values of `key`
+	pub fn remove_key(&self, key: &Key) {
+		self.stored_values.write().unwrap().retain(|&(ref k, _), _| k != key);
+	}
+
+	/// Number of values we republish
+	pub fn republished_count(&self) -> usize {
+		self.stored_values.read().unwrap().len()
+	}
+
+	/// Store `value` only if no value is found for `key` yet.
+	/// Returns whether it was stored.
+	///
+	/// Holders only take the value if nobody else stored one, and it
+	/// counts as stored if most of them took it: of two nodes racing for
+	/// the same key, at most one wins. This is still best effort, holders
+	/// we do not reach may decide differently.
+	pub fn put_if_absent(&mut self, key: Key, value: Vec<u8>) -> Result<bool,PutError> {
+		try!(self.check_len(value.len()));
+		if !self.get(key).is_empty() {
+			debug!("Not storing {}: it already has a value", key);
+			return Ok(false);
+		}
+
+		let mut store = self.store_msg(key, value, None, false, self.config.ttl, None);
+		store.if_absent = true;
+
+		let (accepted, nodes_len) = self.send_to_holders(key, Message::Store(store));
+		debug!("{} of {} nodes let us claim {}", accepted, nodes_len, key);
+		Ok(accepted * 2 > nodes_len)
+	}
+
+	/// Like `put()`, but only requesters holding one of the `acl` keys
+	/// can read the value (see `get_authorized()`)
+	pub fn put_restricted(&mut self, key: Key, value: Vec<u8>, acl: Vec<PublicKey>)
+		-> Result<usize,PutError>
+	{
+		try!(self.check_len(value.len()));
+
+		Ok(self.publish(key, value, Some(acl), PutMode::Append, self.config.ttl, None))
+	}
+
+	/// Like `put()`, but signed with `keypair`. Holders and requesters
+	/// drop the value if the signature does not verify, so other nodes
+	/// can neither tamper with it nor pass off their values as ours.
+	pub fn put_signed(&mut self, key: Key, value: Vec<u8>, keypair: &KeyPair) -> Result<usize,PutError> {
+		try!(self.check_len(value.len()));
+
+		let signature = RecordSignature {
+			public_key: keypair.public,
+			signature:  keypair.sign(&record_msg(&key.id(), &value)[..]),
+		};
+		Ok(self.publish(key, value, None, PutMode::Append, self.config.ttl, Some(signature)))
+	}
+
+	/// Whether a value with this (or no) signature is acceptable, see
+	/// `create_with_verifier()` and `KademliaConfig::require_signatures`
+	fn accepts_signature(&self, key: &Key, value: &[u8], signature: Option<&RecordSignature>) -> bool {
+		match signature {
+			Some(signature) => (self.verifier)(key, value, signature),
+			None => !self.config.require_signatures,
 		}
+	}
 
-		Ok(self.publish(key, value))
+	/// Store a large value by splitting it into chunks stored under derived
+	/// keys and a manifest stored under `key`. Returns how many nodes
+	/// confirmed the manifest.
+	///
+	/// Chunks and manifest have to fit into a Store (at most `max_value_len`
+	/// bytes), so the manifest lists `chunks::max_chunks()` of them at most:
+	/// some 90 KiB with the defaults.
+	pub fn put_large(&mut self, key: Key, value: Vec<u8>) -> Result<usize,PutError> {
+		self.put_large_with_mode(key, value, PutMode::Append)
+	}
+
+	fn put_large_with_mode(&mut self, key: Key, value: Vec<u8>, mode: PutMode) -> Result<usize,PutError> {
+		let max_value_len = self.max_store_value_len(key);
+		let (manifest, chunks) = chunks::split(&key, &value, max_value_len);
+
+		let manifest = manifest.encode();
+		if manifest.len() > max_value_len {
+			return Err(PutError::TooLarge {
+				max:    chunks::max_chunks(max_value_len) * max_value_len,
+				actual: value.len(),
+			});
+		}
+
+		for (chunk_key, chunk) in chunks.into_iter() {
+			self.publish(chunk_key, chunk, None, mode, self.config.ttl, None);
+		}
+
+		Ok(self.publish(key, manifest, None, mode, self.config.ttl, None))
+	}
+
+	/// The longest value (at most `config.max_value_len`) a Store for `key`
+	/// still fits into a datagram with
+	fn max_store_value_len(&self, key: Key) -> usize {
+		let empty = self.store_msg(key, vec![], None, false, self.config.ttl, None);
+		// the length prefix of the value grows with it (bencode writes it in decimal)
+		let overhead = self.server.encoded_len(&Message::Store(empty)) + 8;
+
+		cmp::min(self.config.max_value_len, codec::MAX_MESSAGE_LEN.saturating_sub(overhead))
+	}
+
+	/// Fetch and reassemble a value stored with `put_large()`.
+	/// If `key` has several manifests, the first one that is complete wins.
+	pub fn get_large(&self, key: Key) -> Result<Vec<u8>,LargeGetError> {
+		let manifests:Vec<chunks::Manifest> = self.get(key).iter()
+			.filter_map(|v| chunks::Manifest::decode(&v[..]))
+			.collect();
+
+		let mut result = Err(LargeGetError::NotFound);
+		for manifest in manifests {
+			result = self.get_chunks(&manifest);
+			if result.is_ok() {
+				break;
+			}
+			debug!("Incomplete manifest for {}: {:?}", key, result);
+		}
+		result
+	}
+
+	fn get_chunks(&self, manifest: &chunks::Manifest) -> Result<Vec<u8>,LargeGetError> {
+		let mut value = vec![];
+		for (index, &(ref chunk_key, ref hash)) in manifest.chunks.iter().enumerate() {
+			// do not fetch more than the manifest announced
+			if value.len() as u64 > manifest.total_len {
+				break;
+			}
+
+			// other publishers of `key` use the same chunk keys
+			match self.get(*chunk_key).into_iter().find(|c| chunks::chunk_hash(c) == *hash) {
+				Some(chunk) => value.extend(chunk),
+				None => return Err(LargeGetError::MissingChunk {
+					index: index,
+					key:   *chunk_key,
+				}),
+			}
+		}
+
+		if value.len() as u64 != manifest.total_len {
+			return Err(LargeGetError::LengthMismatch {
+				expected: manifest.total_len,
+				actual:   value.len() as u64,
+			});
+		}
+		Ok(value)
 	}
 
     /// Store a value permanently for `lifetime`
     #[async]
-	pub fn store(&mut self, key: NodeId, value: Vec<u8>, lifetime: u64) -> Result<(),Vec<u8>> {
+	pub fn store(&mut self, key: Key, value: Vec<u8>, lifetime: u64) -> Result<(),PutError> {
 		await!(self.put(key, value));
 
 	    spawn(iter.take(lifetime/timeout).map(|| {
@@ -192,44 +1173,247 @@ impl Kademlia {
         }));
 	}
 
-    #[async]
-	fn publish(&self, key: NodeId, value: Vec<u8>) -> Result<(),()> {
-		let msg = Message::Store(Store {
+	/// Returns the number of nodes that acknowledged the Store
+	fn publish(&self, key: Key, value: Vec<u8>, acl: Option<Vec<PublicKey>>, mode: PutMode,
+		ttl: Duration, signature: Option<RecordSignature>) -> usize
+	{
+		let replace = mode == PutMode::Replace;
+		if replace {
+			// in case we are one of the holders
+			for sender in self.own_entries(&key) {
+				self.external_values.clone().remove_from(&key.id(), &sender);
+			}
+		}
+
+		let msg = Message::Store(self.store_msg(key, value, acl, replace, ttl, signature));
+
+		let (accepted, nodes_len) = self.send_to_holders(key, msg);
+		if accepted > 0 {
+			info!("Published {} on {:?} of {} nodes.", key, accepted, nodes_len);
+		} else {
+			warn!("None of {} nodes stored {}!", nodes_len, key);
+		}
+		accepted
+	}
+
+	fn store_msg(&self, key: Key, value: Vec<u8>, acl: Option<Vec<PublicKey>>, replace: bool,
+		ttl: Duration, signature: Option<RecordSignature>) -> Store
+	{
+		Store {
 			sender_id: self.get_own_id(),
 			cookie:    Self::generate_cookie(),
-			key:       key,
+			key:       key.id(),
 			value:     Value::new(value),
+			acl:       acl,
+			hops_left: MAX_STORE_HOPS,
+			replace:   replace,
+			if_absent: false,
+			ttl_ms:    ttl_ms(ttl),
+			signature: signature,
+		}
+	}
+
+	/// Remove `value` from the nodes we stored it on and keep them from
+	/// storing it again for its remaining TTL, so a republish that is
+	/// still on its way cannot bring it back. Putting the same value again
+	/// only works once that TTL is over.
+	/// Returns the number of nodes that deleted it.
+	pub fn delete(&mut self, key: Key, value: &[u8]) -> usize {
+		self.delete_with_signature(key, value, None)
+	}
+
+	/// Like `delete()`, for a value stored with `put_signed()`: holders only
+	/// delete it if the same `keypair` signs the Delete
+	pub fn delete_signed(&mut self, key: Key, value: &[u8], keypair: &KeyPair) -> usize {
+		let signature = RecordSignature {
+			public_key: keypair.public,
+			signature:  keypair.sign(&delete_msg(&key.id(), value)[..]),
+		};
+		self.delete_with_signature(key, value, Some(signature))
+	}
+
+	fn delete_with_signature(&mut self, key: Key, value: &[u8], signature: Option<RecordSignature>) -> usize {
+		let ttl = {
+			let now = self.clock.now();
+			let stored = self.stored_values.read().unwrap();
+			match stored.get(&(key, value.to_vec())) {
+				Some(&expires) if expires > now => expires.duration_since(now),
+				_ => self.config.ttl,
+			}
+		};
+		self.remove(&key, value);
+
+		// in case we are one of the holders
+		let own_id = self.get_own_id();
+		for sender in self.own_entries(&key) {
+			self.external_values.delete(&key.id(), &sender, value, signature.as_ref(), ttl);
+		}
+
+		let msg = Message::Delete(Delete {
+			sender_id: own_id,
+			cookie:    Self::generate_cookie(),
+			key:       key.id(),
+			value:     Value::new(value.to_vec()),
+			ttl_ms:    ttl_ms(ttl),
+			signature: signature,
 		});
 
-		let nodes = await!(self.find_node(key));
-		let nodes_len = nodes.len();
+		let (accepted, nodes_len) = self.send_to_holders(key, msg);
+		info!("Deleted a value of {} on {} of {} nodes.", key, accepted, nodes_len);
+		accepted
+	}
+
+	/// Senders of the entries we stored for `key` on ourselves, as holder
+	fn own_entries(&self, key: &Key) -> Vec<(SocketAddr, NodeId)> {
+		let own_id = self.get_own_id();
+		self.external_values.get(&key.id()).into_iter()
+			.map(|(sender, _)| sender)
+			.filter(|&(addr, id)| id == own_id && self.kbuckets.is_us(&addr))
+			.collect()
+	}
 
-		for n in nodes {
-			self.server.hit_and_run(n.addr, &msg);
+	/// Send a Store or Delete to the nodes closest to `key`.
+	/// Returns how many of them acknowledged it and how many we asked.
+	fn send_to_holders(&self, key: Key, msg: Message) -> (usize, usize) {
+		self.note_lookup();
+		let mut nodes = self.find_node(key.id());
+		if let Some(max_dist) = self.config.max_replication_distance {
+			nodes.retain(|n| n.distance(&key.id()) <= max_dist);
+		}
+		let nodes_len = nodes.len();
+		if nodes_len == 0 {
+			warn!("Could not find any nodes close to {}!", key);
+			return (0, 0);
 		}
 
-		if nodes_len > 0 {
-			info!("Published {} on {:?} nodes.", enc_id(&key), nodes_len);
-		} else {
-			warn!("Could not find any nodes to publish {}!", enc_id(&key));
+		// every node waits for the timeout anyway, so ask all of them at once
+		let rx = self.server.send_many_request(nodes.into_iter(), msg,
+			self.config.timeout_ms, nodes_len as isize);
+
+		// stop once every node answered or timed out, not just at the last timeout
+		let mut accepted = 0;
+		let mut done = HashSet::new();
+		for (node, resp) in rx.iter() {
+			match resp {
+				Message::StoreResponse(StoreResponse { accepted: true, .. }) => accepted += 1,
+				Message::StoreResponse(_) => debug!("{:?} rejected {}", node, key),
+				Message::Timeout => (),
+				_ => continue,
+			}
+
+			done.insert(node.node_id);
+			if done.len() == nodes_len {
+				break;
+			}
 		}
+		(accepted, nodes_len)
+	}
+
+	/// Remember that a lookup was triggered by a user of this node
+	fn note_lookup(&self) {
+		let mut last_lookup = self.last_lookup.lock().unwrap();
+		*last_lookup = Some(self.clock.now());
+	}
+
+	/// No lookups were triggered by users within `period`
+	fn is_idle(&self, period: Duration) -> bool {
+		let last_lookup = self.last_lookup.lock().unwrap();
+		last_lookup.map(|t| self.clock.now().duration_since(t) >= period).unwrap_or(true)
 	}
 
 	fn generate_cookie() -> Cookie {
-		let cookie = Node::generate_id();
-		assert_eq!(cookie.len(), COOKIE_BYTELEN);
-		cookie
+		message::generate_cookie()
+	}
+
+	/// Ping the contacts we did not hear from for `config.stale_after`
+	/// and remove those that do not answer
+	fn evict_stale_contacts(&mut self) {
+		let stale_after = match self.config.stale_after {
+			None => return,
+			Some(stale_after) => stale_after,
+		};
+
+		let now = self.clock.now();
+		let stale:Vec<Node> = self.kbuckets.get_nodes().into_iter()
+			.filter(|n| n.unseen_for(now) > stale_after)
+			.collect();
+		if stale.is_empty() {
+			return;
+		}
+		debug!("Pinging {} stale contacts", stale.len());
+
+		let req = Message::Ping(Ping {
+			sender_id: self.get_own_id(),
+			cookie:    Self::generate_cookie(),
+		});
+		let rx = self.server.send_many_request(stale.into_iter(), req,
+			self.config.timeout_ms, self.config.alpha);
+
+		// every node yields a Timeout eventually, even after a Pong
+		let mut alive = HashSet::new();
+		for (mut node, resp) in rx.iter() {
+			match resp {
+				Message::Pong(_) => {
+					node.update_last_seen(self.clock.now());
+					alive.insert(node.node_id);
+				},
+				Message::Timeout if !alive.contains(&node.node_id) => {
+					info!("Evicting stale contact {:?}", node);
+					if self.kbuckets.remove(&node) {
+						self.notify(RoutingEventKind::Evicted, node);
+					}
+				},
+				_ => (),
+			}
+		}
+	}
+
+	/// Ask the node at `addr` for its version and optional features
+	pub fn query_capabilities<A: ToSocketAddrs>(&self, addr: A) -> io::Result<PeerCapabilities> {
+		// the NodeId does not matter for a Hello
+		let node = try!(Node::with_clock(addr, Node::generate_id(), self.clock.clone()));
+
+		let req = Message::Hello(Hello {
+			sender_id: self.get_own_id(),
+			cookie:    Self::generate_cookie(),
+		});
+		let rx = self.server.send_many_request(vec![node].into_iter(), req,
+			self.config.timeout_ms, 1);
+
+		match rx.iter().next() {
+			Some((_, Message::HelloAck(ack))) => Ok(PeerCapabilities {
+				version:          ack.version,
+				protocol_version: ack.protocol_version,
+				features:         ack.features,
+			}),
+			_ => Err(io::Error::new(io::ErrorKind::TimedOut, "No HelloAck")),
+		}
+	}
+
+	fn ping(&self, node: &Node) -> bool {
+		let req = Message::Ping(Ping {
+			sender_id: self.get_own_id(),
+			cookie:    Self::generate_cookie(),
+		});
+
+		let rx = self.server.send_many_request(vec![node.clone()].into_iter(), req,
+			self.config.timeout_ms, 1);
+
+		match rx.iter().next() {
+			Some((_, Message::Pong(_))) => true,
+			_ => false,
+		}
 	}
 
 	fn ping_or_replace_with(&mut self, replacement: Node) {
 		let node_list = {
 			let bucket = self.kbuckets.get_bucket(&replacement.node_id);
 
+			// ping the worst contacts first, they are the first to be replaced
 			let mut node_list:Vec<Node> = bucket.map(|b| b.clone()).unwrap_or(vec![]);
+			let now = self.clock.now();
 			node_list.sort_by(|a,b| {
-				let x = *a.last_seen.lock().unwrap();
-				let y = *b.last_seen.lock().unwrap();
-				x.cmp(&y)
+				a.quality(now).partial_cmp(&b.quality(now)).unwrap_or(cmp::Ordering::Equal)
 			});
 			node_list
 		};
@@ -239,53 +1423,77 @@ impl Kademlia {
 			cookie:    Self::generate_cookie(),
 		});
 
-		let rx = self.server.send_many_request(node_list.into_iter(), req, TIMEOUT_MS, ALPHA_PARAM);
+		let rx = self.server.send_many_request(node_list.into_iter(), req,
+			self.config.timeout_ms, self.config.alpha);
 		
+		// every node yields a Timeout eventually, even after a Pong
+		let mut alive = HashSet::new();
 		for (node, resp) in rx {
 			match resp {
-				Message::Pong(_) => (),
+				Message::Pong(_) => {
+					alive.insert(node.node_id);
+				},
+				_ if alive.contains(&node.node_id) => (),
 				_ => {
-					let bucket = self.kbuckets.get_mut_bucket(&replacement.node_id);
-					if bucket.is_none() {
-						return
-					}
-
-					let mut bucket = bucket.unwrap();
-					match bucket.iter().position(|n| *n == node) {
-						None => continue, // hey, where is that node gone?!
-						Some(pos) => {
-							bucket.remove(pos);
-							bucket.push(replacement);
-							return;
-						}
+					// otherwise, hey, where is that node gone?!
+					if self.kbuckets.replace(&node, replacement.clone()) {
+						self.notify(RoutingEventKind::Evicted, node);
+						self.notify(RoutingEventKind::Added, replacement);
+						return;
 					}
 				}
 			}
 		}
+
+		// everybody answered
+		self.notify(RoutingEventKind::Rejected, replacement);
 	}
 
-	fn update_buckets(&mut self, own_id: &NodeId, src: SocketAddr, msg: &Message)
+	/// Contacts closer to `key` than we are, closest first
+	fn closer_nodes(&self, key: &NodeId) -> Vec<Node> {
+		let own_dist = xor(&self.get_own_id(), key);
+
+		self.kbuckets.get_closest_nodes(key, self.config.k).into_iter()
+			.filter(|n| n.dist(key) < own_dist)
+			.collect()
+	}
+
+	fn update_buckets(&mut self, own_id: &NodeId, src: SocketAddr, version: u8, msg: &Message)
 		-> io::Result<()>
 	{
 		match msg {
 			&Message::Timeout => (),
 			_ => {
-				let err_none = io::Error::new(io::ErrorKind::Other, "You don't have a NodeId!");
+				let err_none = io::Error::new(io::ErrorKind::InvalidData, "You don't have a NodeId!");
 				let sender_id = match msg.sender_id() {
 					None     => return Err(err_none),
 					Some(id) => id
 				};
 
-				let err_my_id = io::Error::new(io::ErrorKind::Other, "Hey, you stole my NodeId!");
+				let err_my_id = io::Error::new(io::ErrorKind::AlreadyExists, "Hey, you stole my NodeId!");
 				if sender_id == *own_id {
 					return Err(err_my_id);
 				}
 
-				let mut sender = try!(self.kbuckets.construct_node(src, sender_id));
-				sender.update_last_seen();
+				let mut sender = try!(self.kbuckets.construct_node(src, sender_id, self.clock.clone()));
+				sender.update_last_seen(self.clock.now());
+				sender.set_protocol_version(version);
+
+				// responses are matched to our requests in Server::send_many_request()
+				match *msg {
+					Message::Ping(_) | Message::FindNode(_) | Message::FindValue(_)
+					| Message::FindValues(_) | Message::Store(_) | Message::Listen(_)
+					| Message::Delete(_)
+					| Message::Hello(_) => sender.mark_inbound(),
+					_ => (),
+				}
 
-				ignore(self.kbuckets.add(sender)
-					.map_err(|sender| self.ping_or_replace_with(sender)));
+				let is_new = !self.is_contact(&sender);
+				match self.kbuckets.add(sender.clone()) {
+					Ok(()) if is_new && self.is_contact(&sender) => self.notify(RoutingEventKind::Added, sender),
+					Ok(()) => (),
+					Err(sender) => self.ping_or_replace_with(sender),
+				}
 			}
 		}
 
@@ -294,12 +1502,13 @@ impl Kademlia {
 		Ok(())
 	}
 
-	fn handle_message(&mut self, src: SocketAddr, msg: Message)
+	/// `version` is the protocol version the sender speaks
+	fn handle_message(&mut self, src: SocketAddr, version: u8, msg: Message)
 		-> io::Result<()>
 	{
 		let own_id = self.get_own_id();
 
-		try!(self.update_buckets(&own_id, src, &msg));
+		try!(self.update_buckets(&own_id, src, version, &msg));
 
 		match msg {
 			Message::Ping(ping) => {
@@ -309,8 +1518,21 @@ impl Kademlia {
 				};
 				self.server.send_response(src, &Message::Pong(pong));
 			}
+			Message::Hello(hello) => {
+				let ack = HelloAck {
+					sender_id:        own_id,
+					cookie:           hello.cookie,
+					version:          env!("CARGO_PKG_VERSION").to_string(),
+					protocol_version: PROTOCOL_VERSION,
+					features:         OWN_FEATURES,
+				};
+				self.server.send_response(src, &Message::HelloAck(ack));
+			}
 			Message::FindNode(find_node) => {
-				let node_list = self.kbuckets.get_closest_nodes(&find_node.key, K_PARAM);
+				// prefer contacts others can reach, too
+				let mut node_list = self.kbuckets.get_closest_nodes(&find_node.key, 2*self.config.k);
+				node_list.sort_by_key(|n| n.is_inbound_only());
+				node_list.truncate(self.config.k);
 				let count = node_list.len();
 
 				for node in node_list.into_iter() {
@@ -324,22 +1546,39 @@ impl Kademlia {
 				}
 			},
 			Message::FindValue(find_value) => {
-				let value_list = self.external_values.get(&find_value.key);
+				// values with an ACL are only returned to requesters that prove membership
+				let requester = match find_value.proof {
+					Some(ref proof) => {
+						let msg = access_proof_msg(&find_value.cookie, &find_value.key, &own_id);
+						if signing::verify(&msg[..], &proof.public_key, &proof.signature[..]) {
+							Some(proof.public_key)
+						} else {
+							debug!("Invalid access proof from {}", src);
+							None
+						}
+					},
+					None => None,
+				};
+
+				let max_values = self.config.max_values_per_response;
+				let value_list = self.external_values.get_newest(&find_value.key, max_values,
+					requester.as_ref());
 
 				if value_list.len() > 0 {
 					let count = value_list.len();
 
-					for (_, value) in value_list.into_iter() {
+					for entry in value_list.into_iter() {
 						let found_value = FoundValue {
 							sender_id:   own_id,
 							cookie:      find_value.cookie,
 							value_count: count,
-							value:       Value::new(value),
+							value:       Value::new(entry.value),
+							signature:   entry.signature,
 						};
 						self.server.send_response(src, &Message::FoundValue(found_value));
 					}
 				} else {
-					let node_list = self.kbuckets.get_closest_nodes(&find_value.key, K_PARAM);
+					let node_list = self.kbuckets.get_closest_nodes(&find_value.key, self.config.k);
 					let count = node_list.len();
 
 					for node in node_list.into_iter() {
@@ -354,11 +1593,79 @@ impl Kademlia {
 				}
 			},
 			Message::Store(store) => {
-				if store.value.len() <= MAX_VALUE_LEN {
+				let closer = self.closer_nodes(&store.key);
+				let is_far = closer.len() >= self.config.k;
+
+				match self.config.far_store_policy {
+					FarStorePolicy::Reject if is_far => {
+						debug!("Rejecting Store for {}, we are too far away", enc_id(&store.key));
+						self.send_store_response(src, store.cookie, false);
+						return Ok(());
+					},
+					FarStorePolicy::Forward if is_far && store.hops_left > 0 => {
+						debug!("Forwarding Store for {}, we are too far away", enc_id(&store.key));
+						let forward = Message::Store(Store {
+							sender_id: own_id,
+							cookie:    Self::generate_cookie(),
+							hops_left: store.hops_left - 1,
+							.. store
+						});
+
+						for n in closer.iter().take(self.config.alpha as usize) {
+							self.server.hit_and_run(n.addr, &forward);
+						}
+						// we did not store it ourselves
+						self.send_store_response(src, store.cookie, false);
+						return Ok(());
+					},
+					_ => (),
+				}
+
+				let empty_value_policy = self.config.empty_value_policy;
+
+				let accepted = if !self.accepts_signature(&Key::new(store.key), &store.value, store.signature.as_ref()) {
+					debug!("Rejecting value for {}, its signature is missing or invalid", enc_id(&store.key));
+					false
+				} else if self.external_values.is_deleted(&store.key, &(src, store.sender_id), &store.value) {
+					debug!("Rejecting value for {}, it was deleted", enc_id(&store.key));
+					false
+				} else if store.ttl_ms == 0 {
+					debug!("Rejecting value for {}, it expired already", enc_id(&store.key));
+					false
+				} else if store.value.len() == 0 && empty_value_policy == EmptyValuePolicy::Delete {
+					debug!("Got empty value, removing the values {} stored for {}", src, enc_id(&store.key));
+					self.external_values.remove_from(&store.key, &(src, store.sender_id));
+					true
+				} else if store.value.len() == 0 && empty_value_policy == EmptyValuePolicy::Reject {
+					debug!("Rejecting empty value for {}", enc_id(&store.key));
+					false
+				} else if self.external_values.exceeds_quota(&store.key, &(src, store.sender_id),
+						store.value.len(), self.config.max_keys_per_source, self.config.max_bytes_per_source) {
+					debug!("Rejecting value for {}, {} exceeds its quota", enc_id(&store.key), src.ip());
+					false
+				} else if store.value.len() <= self.config.max_value_len {
 					let sender = (src, store.sender_id);
-					self.external_values.put(store.key, sender, (*store.value).clone());
+					let is_restricted = store.acl.is_some();
+					if store.replace {
+						debug!("Replacing the values {} stored for {}", src, enc_id(&store.key));
+						self.external_values.remove_from(&store.key, &sender);
+					}
+					let ttl = cmp::min(Duration::from_millis(store.ttl_ms as u64), self.config.ttl);
+					let stored = if store.if_absent {
+						self.external_values.put_if_absent(store.key, sender, (*store.value).clone(),
+							store.acl.clone(), Some(ttl), store.signature.clone())
+					} else {
+						self.external_values.put_signed(store.key, sender, (*store.value).clone(),
+							store.acl.clone(), Some(ttl), store.signature.clone());
+						true
+					};
+					if !stored {
+						debug!("Rejecting value for {}, someone else claimed it", enc_id(&store.key));
+					}
 
-					for ((dst, _), cookie_vec) in self.listeners.get(&store.key) {
+					// listeners cannot prove they may read restricted values
+					let listeners = if is_restricted || !stored { vec![] } else { self.listeners.get(&store.key) };
+					for ((dst, _), cookie_vec) in listeners {
 					    let mut cookie = [0; COOKIE_BYTELEN];
 					    cookie.copy_from_slice(&cookie_vec);
 
@@ -367,73 +1674,237 @@ impl Kademlia {
 							cookie:      cookie,
 							value_count: 1,
 							value:       Value::new((*store.value).clone()),
+							signature:   store.signature.clone(),
                         };
                         self.server.send_response(dst, &Message::FoundValue(found_value));
 					}
+					stored
+				} else {
+					debug!("Rejecting value for {}, it is too large", enc_id(&store.key));
+					false
+				};
+
+				self.send_store_response(src, store.cookie, accepted);
+			},
+			Message::Delete(delete) => {
+				let ttl = cmp::min(Duration::from_millis(delete.ttl_ms as u64), self.config.ttl);
+				let sender = (src, delete.sender_id);
+				let deleted = self.external_values.delete(&delete.key, &sender, &delete.value,
+					delete.signature.as_ref(), ttl);
+				if deleted {
+					debug!("Deleted a value of {}", enc_id(&delete.key));
+				} else {
+					debug!("Not deleting a value of {}, {} did not store it or may not delete it",
+						enc_id(&delete.key), src);
 				}
+				self.send_store_response(src, delete.cookie, deleted);
 			},
 			Message::Listen(listen) => {
 				let sender = (src, listen.sender_id);
                 self.listeners.put(listen.key, sender, listen.cookie.to_vec())
 			},
+			Message::FindValues(find_values) => {
+				let max_values = self.config.max_values_per_response;
+
+				let mut entries = vec![];
+				let mut total_len = 0;
+				'keys: for key in find_values.keys.iter().take(MAX_BATCH_KEYS) {
+					for entry in self.external_values.get_newest(key, max_values, None) {
+						let entry = (*key, Value::new(entry.value), entry.signature);
+						let len = entry_len(&entry);
+						if total_len + len > MAX_BATCH_TOTAL_LEN {
+							debug!("FindValues: answer to {} would exceed {} bytes, truncating it",
+								src, MAX_BATCH_TOTAL_LEN);
+							break 'keys;
+						}
+						total_len += len;
+						entries.push(entry);
+					}
+				}
+				self.send_found_values(src, find_values.cookie, entries);
+			},
 			Message::Timeout
 			| Message::Pong(_)
 			| Message::FoundNode(_)
-			| Message::FoundValue(_) => (),
+			| Message::FoundValue(_)
+			| Message::FoundValues(_)
+			| Message::StoreResponse(_)
+			| Message::HelloAck(_) => (),
 		};
 
 		Ok(())
 	}
 
-	fn find_value(&self, key: NodeId) -> impl Stream<Vec<u8>> {
+	/// Send the entries in as few FoundValues datagrams as possible
+	/// (at least one, so the requester knows if there is nothing)
+	fn send_found_values(&self, dst: SocketAddr, cookie: Cookie,
+		entries: Vec<(NodeId, Value, Option<RecordSignature>)>)
+	{
+		let own_id = self.get_own_id();
+		let entry_count = entries.len();
+
+		let mut chunks = vec![vec![]];
+		let mut chunk_len = 0;
+		for entry in entries.into_iter() {
+			let len = entry_len(&entry);
+
+			if chunk_len + len > MAX_BATCH_RESPONSE_LEN && chunk_len > 0 {
+				chunks.push(vec![]);
+				chunk_len = 0;
+			}
+			chunk_len += len;
+			chunks.last_mut().unwrap().push(entry);
+		}
+
+		for chunk in chunks.into_iter() {
+			let found_values = FoundValues {
+				sender_id:   own_id,
+				cookie:      cookie,
+				entry_count: entry_count,
+				entries:     chunk,
+			};
+			self.server.send_response(dst, &Message::FoundValues(found_values));
+		}
+	}
+
+	fn send_store_response(&self, dst: SocketAddr, cookie: Cookie, accepted: bool) {
+		let resp = StoreResponse {
+			sender_id: self.get_own_id(),
+			cookie:    cookie,
+			accepted:  accepted,
+		};
+		self.server.send_response(dst, &Message::StoreResponse(resp));
+	}
+
+	/// `budget` limits the number of queries in flight (instead of
+	/// `config.alpha`), see `get_many()`
+	fn find_value(&self, key: Key, tracer: Option<Tracer>, keypair: Option<KeyPair>, meter: CostMeter,
+		budget: Option<Arc<Semaphore>>) -> impl Stream<Vec<u8>>
+	{
         let own_id = self.get_own_id();
+		self.kbuckets.touch(&key.id(), self.clock.now());
 		let closest = self.kbuckets.get_nodes();
 	    debug!("FindValue: {:?} initial nodes", closest.len());
 
-	    let iter = ClosestNodesIter::new(key, K_PARAM, closest);
-
-	    let req = Message::FindValue(FindValue {
-		    cookie:    Self::generate_cookie(),
-		    sender_id: self.get_own_id(),
-		    key:       key,
-	    });
-	    let rx = self.server.send_many_request(iter.clone(), req, TIMEOUT_MS, ALPHA_PARAM); //chain channels??
+	    let iter = ClosestNodesIter::new(key.id(), self.config.k, closest);
+
+	    let cookie = Self::generate_cookie();
+	    // the proof only works at the node it is signed for
+	    let request_for = move |recipient: &NodeId| {
+		    let proof = keypair.as_ref().map(|k| AccessProof {
+			    public_key: k.public,
+			    signature:  k.sign(&access_proof_msg(&cookie, &key.id(), recipient)[..]),
+		    });
+		    Message::FindValue(FindValue {
+			    cookie:    cookie,
+			    sender_id: own_id,
+			    key:       key.id(),
+			    proof:     proof,
+		    })
+	    };
+	    let req = request_for(&own_id);
+
+	    let query_tracer = tracer.clone();
+	    let query_meter = meter.clone();
+	    let budget_meter = meter.clone();
+	    let deadline = self.clock.now() + self.config.lookup_timeout;
+	    let deadline_clock = self.clock.clone();
+	    let req_len = self.server.encoded_len(&req);
+	    let queries = iter.clone()
+		    .take(self.config.max_lookup_queries)
+		    .take_while(move |_| !budget_meter.exceeded() && deadline_clock.now() < deadline)
+		    .inspect(move |n| {
+			    if let Some(ref t) = query_tracer {
+				    t.query(n, &cookie);
+			    }
+			    query_meter.query(req_len);
+		    });
+	    let sem = budget.unwrap_or_else(|| Arc::new(Semaphore::new(self.config.alpha)));
+	    let rx = self.server.send_many_request_with(queries, move |n: &Node| request_for(&n.node_id),
+		    self.config.timeout_ms, sem, Some(meter.clone())); //chain channels??
 
         let (result_tx, result_rx) = mpsc::channel(2048);
 
 	    let mut values = HashSet::new();
+	    let mut value_bytes = 0;
 	    let mut value_nodes = HashSet::new();
-        let timeout = Sleep::new(Duration::from_millis(TIMEOUT_MS));
+	    let max_value_bytes = self.config.max_lookup_value_bytes;
+	    let policy = self.config.conflict_policy;
+	    let mut support = HashMap::new();
+        let timeout = Sleep::new(Duration::from_millis(self.config.timeout_ms as u64));
 
         #[async]
         for resp in rx.select2(timeout) {
-            if let Either::A((_, resp), timeout) = res? {
+            if let Either::A((sender, resp), timeout) = res? {
+			    if let Some(ref t) = tracer {
+				    t.response(&sender, &resp);
+			    }
+			    if resp != Message::Timeout {
+				    meter.response(&sender.node_id, self.server.encoded_len(&resp));
+			    }
+
 			    match resp {
 				    Message::FoundNode(found_node) => {
 					    let node = found_node.node;
 
-					    if node.node_id != own_id {
+					    if node.node_id != own_id && !Node::is_link_local(&node.addr) {
 						    iter.add_node(node);
 					    }
 				    },
-				    Message::FoundValue(FoundValue { sender_id: id, value: Value { data: v }, .. }) => {
+				    Message::FoundValue(FoundValue { sender_id: id, value: Value { data: v }, signature, .. }) => {
+					    if !self.accepts_signature(&key, &v, signature.as_ref()) {
+						    debug!("FindValue: dropping value for {} from {:?}, bad signature", key, sender);
+						    continue;
+					    }
 					    if !values.contains(&v) {
-				            //result_tx.send(v.clone()).unwrap();
-				            yield v.clone();
-				            values.insert(v);
+						    if value_bytes + v.len() > max_value_bytes {
+							    warn!("FindValue: values for {} exceed {} bytes, stopping lookup",
+								    key, max_value_bytes);
+							    break;
+						    }
+						    value_bytes += v.len();
+
+						    if policy != ConflictPolicy::Majority {
+				                //result_tx.send(v.clone()).unwrap();
+				                yield v.clone();
+						    }
+				            values.insert(v.clone());
+					    }
+					    // a node answering twice still counts once
+					    support.entry(v).or_insert_with(HashSet::new).insert(sender.node_id);
+
+					    if policy == ConflictPolicy::First {
+						    break;
 					    }
 
                         value_nodes.insert(id);
-					    if value_nodes.len() == K_PARAM {
-						    return;
+					    if value_nodes.len() == self.config.k {
+						    break;
 					    }
 				    },
 			    }
 
+			    if meter.exceeded() {
+				    warn!("FindValue: lookup for {} exceeded its byte budget, stopping ({:?})",
+					    key, meter.cost());
+				    break;
+			    }
+			    if self.clock.now() >= deadline {
+				    warn!("FindValue: lookup for {} ran out of time, stopping ({:?})",
+					    key, meter.cost());
+				    break;
+			    }
+
                 timeout.reset();
             }
 
         }
+
+	    if policy == ConflictPolicy::Majority {
+		    if let Some(v) = majority_value(support) {
+			    yield v;
+		    }
+	    }
         handle.spawn(future);
 
         result_rx
@@ -441,18 +1912,31 @@ impl Kademlia {
 
     #[async]
 	fn find_node(&self, key: NodeId) -> Result<Vec<Node>> {
+		self.kbuckets.touch(&key, self.clock.now());
 		let closest = self.kbuckets.get_nodes();
 
 		debug!("FindNode: {:?} initial nodes", closest.len());
-		let iter = ClosestNodesIter::new(key, K_PARAM, closest);
+		let iter = ClosestNodesIter::new(key, self.config.k, closest);
 
 		let req = Message::FindNode(FindNode {
 			cookie:    Self::generate_cookie(),
 			sender_id: self.get_own_id(),
 			key:       key,
 		});
-		let rx = self.server.send_many_request(iter.clone(), req, TIMEOUT_MS, ALPHA_PARAM); //chain channels??
-        let timeout = Sleep::new(Duration::from_millis(4*TIMEOUT_MS));
+		let meter = self.cost_meter();
+		let query_meter = meter.clone();
+		let budget_meter = meter.clone();
+		let deadline = self.clock.now() + self.config.lookup_timeout;
+		let deadline_clock = self.clock.clone();
+		let req_len = self.server.encoded_len(&req);
+		let queries = iter.clone()
+			.take(self.config.max_lookup_queries)
+			.take_while(move |_| !budget_meter.exceeded() && deadline_clock.now() < deadline)
+			.inspect(move |_| query_meter.query(req_len));
+
+		let rx = self.server.send_many_request_metered(queries, req,
+			self.config.timeout_ms, self.config.alpha, Some(meter.clone())); //chain channels??
+        let timeout = Sleep::new(Duration::from_millis(4*self.config.timeout_ms as u64));
 
         rx.select(timeout)
           .inspect(|n| if node.node_id != own_id { iter.add_node(n) })
@@ -461,11 +1945,15 @@ impl Kademlia {
 
 	    let mut nodes_online = vec![];
 	    let mut failed = 0;
-	    while failed < TIMEOUT_MS/250 {
+	    while failed < self.config.timeout_ms/250 && !meter.exceeded() && self.clock.now() < deadline {
 		    for (sender, resp) in rx.iter() {
 			    debug!("resp={:?}", resp);
 			    failed = 0;
 
+			    if resp != Message::Timeout {
+				    meter.response(&sender.node_id, self.server.encoded_len(&resp));
+			    }
+
                 if let Message::FoundNode(found_node) = resp {
 				    nodes_online.push(sender);
 				    nodes_online.sort_by(asc_dist_order!(key));
@@ -474,18 +1962,223 @@ impl Kademlia {
 				    let own_id = self.get_own_id();
 				    let node = found_node.node;
 
-				    if node.node_id != own_id {
+				    if node.node_id != own_id && !Node::is_link_local(&node.addr) {
 					    iter.add_node(node);
 				    }
 			    };
+
+			    if meter.exceeded() {
+				    warn!("FindNode: lookup for {} exceeded its byte budget, stopping ({:?})",
+					    enc_id(&key), meter.cost());
+				    break;
+			    }
+			    if self.clock.now() >= deadline {
+				    warn!("FindNode: lookup for {} ran out of time, stopping ({:?})",
+					    enc_id(&key), meter.cost());
+				    break;
+			    }
 		    }
 
-		    sleep(Duration::from_millis(250));
+		    self.clock.sleep(Duration::from_millis(250));
 		    failed += 1;
 	    }
+	    debug!("FindNode: lookup for {} done ({:?})", enc_id(&key), meter.cost());
 
-	    nodes_online.truncate(K_PARAM);
+	    nodes_online.truncate(self.config.k);
 	    nodes_online
 	}
+
+	/// Like `find_node()`, but along with each node's distance to `key`
+	/// (closest first)
+	pub fn find_node_with_distances(&self, key: NodeId) -> Vec<(Node, Distance)> {
+		let mut nodes = self.find_node(key);
+		nodes.sort_by(asc_dist_order!(key));
+
+		nodes.into_iter()
+			.map(|n| {
+				let dist = n.distance(&key);
+				(n, dist)
+			})
+			.collect()
+	}
+
+	/// Like `find_node()`, but returns as soon as a response contains a node
+	/// closer to `key` than the closest node we knew before, or after the
+	/// first round of requests completed (instead of converging to the K
+	/// closest nodes).
+	pub fn find_node_any(&self, key: NodeId) -> Option<Node> {
+		self.note_lookup();
+		let own_id = self.get_own_id();
+		let closest = self.kbuckets.get_closest_nodes(&key, self.config.k);
+		let best = closest.first().cloned();
+
+		debug!("FindNodeAny: {:?} initial nodes", closest.len());
+		let first_round = cmp::min(closest.len(), self.config.alpha as usize);
+
+		let req = Message::FindNode(FindNode {
+			cookie:    Self::generate_cookie(),
+			sender_id: own_id,
+			key:       key,
+		});
+		let rx = self.server.send_many_request(closest.into_iter(), req,
+			self.config.timeout_ms, self.config.alpha);
+
+		let mut received = HashMap::new();
+		let mut done = 0;
+
+		for (sender, resp) in rx.iter() {
+			match resp {
+				Message::FoundNode(found_node) => {
+					let count = received.entry(sender.node_id).or_insert(0);
+					*count += 1;
+					if *count == found_node.node_count {
+						done += 1;
+					}
+
+					let node = found_node.node;
+					let is_closer = best.as_ref()
+						.map(|b| node.dist(&key) < b.dist(&key))
+						.unwrap_or(true);
+
+					if node.node_id != own_id && !Node::is_link_local(&node.addr) && is_closer {
+						return Some(node);
+					}
+				},
+				Message::Timeout => done += 1,
+				_ => (),
+			}
+
+			if done >= first_round {
+				break;
+			}
+		}
+
+		best
+	}
+
+	/// Ask `node` for the values of several keys with a single FindValues
+	/// request. Nodes that do not understand FindValues yet are asked with
+	/// one FindValue per key instead.
+	pub fn find_values_at(&self, node: &Node, keys: Vec<Key>) -> HashMap<Key, Vec<Vec<u8>>> {
+		let mut result:HashMap<Key, Vec<Vec<u8>>> = keys.iter()
+			.map(|k| (*k, vec![]))
+			.collect();
+
+		for batch in keys.chunks(MAX_BATCH_KEYS) {
+			let req = Message::FindValues(FindValues {
+				cookie:    Self::generate_cookie(),
+				sender_id: self.get_own_id(),
+				keys:      batch.iter().map(|k| k.id()).collect(),
+			});
+			let rx = self.server.send_many_request(vec![node.clone()].into_iter(), req,
+				self.config.timeout_ms, 1);
+
+			let mut answered = false;
+			let mut received = 0;
+			for (_, resp) in rx.iter() {
+				match resp {
+					Message::FoundValues(found_values) => {
+						answered = true;
+						received += found_values.entries.len();
+
+						for (key, value, signature) in found_values.entries.into_iter() {
+							let key = Key::new(key);
+							if !self.accepts_signature(&key, &value.data, signature.as_ref()) {
+								debug!("FindValues: dropping value for {} from {:?}, bad signature", key, node);
+								continue;
+							}
+							if let Some(values) = result.get_mut(&key) {
+								values.push(value.data);
+							}
+						}
+
+						if received >= found_values.entry_count {
+							break;
+						}
+					},
+					_ => break,
+				}
+			}
+
+			if !answered {
+				debug!("{:?} does not support FindValues, falling back to FindValue", node);
+				for key in batch.iter() {
+					result.insert(*key, self.find_value_at(node, *key));
+				}
+			}
+		}
+
+		result
+	}
+
+	/// Ask a single node for the values it stores for `key`
+	fn find_value_at(&self, node: &Node, key: Key) -> Vec<Vec<u8>> {
+		let req = Message::FindValue(FindValue {
+			cookie:    Self::generate_cookie(),
+			sender_id: self.get_own_id(),
+			key:       key.id(),
+			proof:     None,
+		});
+		let rx = self.server.send_many_request(vec![node.clone()].into_iter(), req,
+			self.config.timeout_ms, 1);
+
+		let mut values = vec![];
+		for (_, resp) in rx.iter() {
+			match resp {
+				Message::FoundValue(found_value) => {
+					if self.accepts_signature(&key, &found_value.value, found_value.signature.as_ref()) {
+						values.push(found_value.value.data);
+					}
+					if values.len() >= found_value.value_count {
+						break;
+					}
+				},
+				_ => break, // FoundNode (no values) or Timeout
+			}
+		}
+
+		values
+	}
 }
  
+
+/// Roughly the encoded size of a FoundValues entry
+fn entry_len(entry: &(NodeId, Value, Option<RecordSignature>)) -> usize {
+	let &(ref key, ref value, ref signature) = entry;
+	let signature_len = signature.as_ref()
+		.map(|s| s.public_key.len() + s.signature.len() + 16)
+		.unwrap_or(0);
+
+	key.len() + value.len() + 16 + signature_len
+}
+
+/// The value with the most supporting nodes, the smallest one on a tie
+fn majority_value(support: HashMap<Vec<u8>, HashSet<NodeId>>) -> Option<Vec<u8>> {
+	support.into_iter()
+		.max_by(|a, b| a.1.len().cmp(&b.1.len()).then_with(|| b.0.cmp(&a.0)))
+		.map(|(value, _)| value)
+}
+
+#[cfg(test)]
+use node::NODEID_BYTELEN;
+
+#[cfg(test)]
+fn nodes(ids: &[u8]) -> HashSet<NodeId> {
+	ids.iter().map(|&i| [i; NODEID_BYTELEN]).collect()
+}
+
+#[test]
+fn majority() {
+	let mut support = HashMap::new();
+	support.insert(vec![1,2,3], nodes(&[1, 2, 3]));
+	support.insert(vec![4,5,6], nodes(&[4, 5]));
+
+	assert_eq!(majority_value(support), Some(vec![1,2,3]));
+	assert_eq!(majority_value(HashMap::new()), None);
+
+	let mut support = HashMap::new();
+	support.insert(vec![4,5,6], nodes(&[1, 2]));
+	support.insert(vec![1,2,3], nodes(&[3, 4]));
+	support.insert(vec![7,8,9], nodes(&[5]));
+	assert_eq!(majority_value(support), Some(vec![1,2,3]));
+}
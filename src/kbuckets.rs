@@ -1,33 +1,89 @@
 use std::sync::{Arc,Mutex,MutexGuard};
-use std::net::{SocketAddr};
+use std::net::{SocketAddr, IpAddr};
+use std::collections::VecDeque;
 use std::io;
 
 use node::{Node, NodeId, NODEID_BYTELEN, xor};
+use clock::{Duration, Instant, SharedClock};
+use config::{KademliaConfig, AddressPolicy};
+use utils;
+#[cfg(test)]
 use kademlia::K_PARAM;
 
 #[cfg(test)]
 use utils::ignore;
 
+/// Number of changes we remember for `changes_since()`
+const MAX_CHANGES: usize = 1024;
+
+/// A change of the routing table, see `KBuckets::changes_since()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContactChange {
+	Added(Node),
+	Removed(Node),
+	/// e.g. the contact's hostname resolves to a new address
+	Updated(Node),
+}
+
+struct ChangeLog {
+	seq:     u64,
+	changes: VecDeque<(u64, ContactChange)>,
+}
+
 #[derive(Clone)]
 pub struct KBuckets {
 	own_id:  Arc<Mutex<NodeId>>,
-	buckets: Vec<Arc<Mutex<Vec<Node>>>>
+	buckets: Vec<Arc<Mutex<Vec<Node>>>>,
+	k:       usize,
+	changes: Arc<Mutex<ChangeLog>>,
+	/// when each bucket was last looked up, see `touch()`
+	refreshed: Arc<Mutex<Vec<Option<Instant>>>>,
+	address_policy: AddressPolicy,
+	/// the address we are bound to
+	local_addr: Option<SocketAddr>,
+	/// our interfaces' addresses, if `local_addr` is unspecified
+	own_ips: Arc<Vec<IpAddr>>,
 }
 
 impl KBuckets {
-	pub fn new(own_id: Arc<Mutex<NodeId>>) -> KBuckets {
+	/// Each bucket holds up to `k` nodes
+	#[allow(dead_code)]
+	pub fn new(own_id: Arc<Mutex<NodeId>>, k: usize) -> KBuckets {
+		Self::with_address_policy(own_id, k, KademliaConfig::default().address_policy, None)
+	}
+
+	/// Like `new()`, but only adds contacts whose address `policy` accepts
+	/// and that are not us at `local_addr`
+	pub fn with_address_policy(own_id: Arc<Mutex<NodeId>>, k: usize, policy: AddressPolicy,
+		local_addr: Option<SocketAddr>) -> KBuckets
+	{
 		let buckets = (0..NODEID_BYTELEN*8)
-			.map(|_| Arc::new(Mutex::new(vec![])))
+			.map(|_| Arc::new(Mutex::new(Vec::with_capacity(k))))
 			.collect();
+		let own_ips = match local_addr {
+			Some(local) if local.ip().is_unspecified() => utils::interface_ips(),
+			_ => vec![],
+		};
 
 		KBuckets {
 			own_id:  own_id,
 			buckets: buckets,
+			k:       k,
+			changes: Arc::new(Mutex::new(ChangeLog {
+				seq:     0,
+				changes: VecDeque::new(),
+			})),
+			refreshed: Arc::new(Mutex::new(vec![None; NODEID_BYTELEN*8])),
+			address_policy: policy,
+			local_addr: local_addr,
+			own_ips: Arc::new(own_ips),
 		}
 	}
 
-	pub fn construct_node(&mut self, addr: SocketAddr, node_id: NodeId) -> io::Result<Node> {
-		let default = try!(Node::new(addr, node_id));
+	pub fn construct_node(&mut self, addr: SocketAddr, node_id: NodeId, clock: SharedClock)
+		-> io::Result<Node>
+	{
+		let default = try!(Node::with_clock(addr, node_id, clock));
 		let err = io::Error::new(io::ErrorKind::Other, "Hey, you stole my NodeId!");
 
 		match self.get_bucket(&node_id) {
@@ -57,6 +113,44 @@ impl KBuckets {
 		None
 	}
 
+	/// A lookup for `key` at `now` refreshes the bucket `key` falls into
+	pub fn touch(&self, key: &NodeId, now: Instant) {
+		if let Some(idx) = self.get_bucket_idx(key) {
+			self.refreshed.lock().unwrap()[idx] = Some(now);
+		}
+	}
+
+	/// Buckets that were not looked up within `period`. Buckets closer
+	/// to us than our closest contact are skipped: looking up our own
+	/// NodeId covers them.
+	pub fn stale_buckets(&self, now: Instant, period: Duration) -> Vec<usize> {
+		let closest = match self.buckets.iter().position(|b| !b.lock().unwrap().is_empty()) {
+			None => return vec![],
+			Some(idx) => idx,
+		};
+
+		let refreshed = self.refreshed.lock().unwrap();
+		(closest..self.buckets.len())
+			.filter(|&idx| refreshed[idx].map(|t| now.duration_since(t) >= period).unwrap_or(true))
+			.collect()
+	}
+
+	/// A random NodeId that falls into bucket `idx`
+	pub fn random_id_in_bucket(&self, idx: usize) -> NodeId {
+		// the highest bit set in the distance to us determines the bucket
+		let byte = NODEID_BYTELEN - 1 - idx/8;
+		let bit = idx % 8;
+
+		let mut dist = Node::generate_id();
+		for b in dist[..byte].iter_mut() {
+			*b = 0;
+		}
+		dist[byte] = (dist[byte] & ((1 << bit) - 1)) | (1 << bit);
+
+		let own_id = self.own_id.lock().unwrap();
+		xor(&own_id, &dist)
+	}
+
 	pub fn estimate_peers_in_network(&self) -> usize {
         let mut estimate = 2;
 
@@ -83,19 +177,114 @@ impl KBuckets {
 			.map(|b| b.lock().unwrap())
 	}
 
+	/// Would `add()` take a contact at `addr`?
+	pub fn accepts(&self, addr: &SocketAddr) -> bool {
+		!self.is_us(addr) && self.address_policy.accepts(addr)
+	}
+
+	/// Is `addr` our own address (see `utils::is_own_addr()`)?
+	pub fn is_us(&self, addr: &SocketAddr) -> bool {
+		match self.local_addr {
+			None => false,
+			Some(local) => utils::is_own_addr(addr, &local, &self.own_ips),
+		}
+	}
+
+	/// Nodes at addresses we do not accept (see `accepts()`) are ignored
+	/// silently, like returning Ok(())
 	pub fn add(&mut self, node: Node) -> Result<(), Node> {
+		if !self.accepts(&node.addr) {
+			debug!("Not adding {:?}, its address is not accepted", node);
+			return Ok(());
+		}
+		let node = self.adopt_hostname(node);
+
+		let k = self.k;
 		match self.get_mut_bucket(&node.node_id) {
-			None => Ok(()), // ignore silently
-			Some(ref b) if b.contains(&node) => Ok(()),
+			None => return Ok(()), // ignore silently
+			Some(ref b) if b.contains(&node) => {
+				// keep what we measured while it was not in the routing table
+				for n in b.iter().filter(|n| **n == node) {
+					n.adopt_rtt(&node);
+				}
+				return Ok(());
+			},
 			Some(ref mut b) => {
-				if b.len() < K_PARAM {
-					b.push(node);
-					Ok(())
-				} else {
-					Err(node)
+				if b.len() >= k {
+					return Err(node);
 				}
+				b.push(node.clone());
 			}
 		}
+
+		self.record(ContactChange::Added(node));
+		Ok(())
+	}
+
+	/// Replace `old` by `new` (in the same bucket).
+	/// Returns false if `old` is not in the routing table (anymore).
+	pub fn replace(&mut self, old: &Node, new: Node) -> bool {
+		let removed = match self.get_mut_bucket(&new.node_id) {
+			None => return false,
+			Some(mut bucket) => {
+				match bucket.iter().position(|n| n == old) {
+					None => return false,
+					Some(pos) => {
+						let removed = bucket.remove(pos);
+						bucket.push(new.clone());
+						removed
+					}
+				}
+			}
+		};
+
+		self.record(ContactChange::Removed(removed));
+		self.record(ContactChange::Added(new));
+		true
+	}
+
+	/// Returns false if `node` is not in the routing table
+	pub fn remove(&mut self, node: &Node) -> bool {
+		let removed = match self.get_mut_bucket(&node.node_id) {
+			None => return false,
+			Some(mut bucket) => {
+				match bucket.iter().position(|n| n == node) {
+					None => return false,
+					Some(pos) => bucket.remove(pos),
+				}
+			}
+		};
+
+		self.record(ContactChange::Removed(removed));
+		true
+	}
+
+	fn record(&self, change: ContactChange) {
+		let mut log = self.changes.lock().unwrap();
+
+		log.seq += 1;
+		let seq = log.seq;
+		log.changes.push_back((seq, change));
+
+		if log.changes.len() > MAX_CHANGES {
+			log.changes.pop_front();
+		}
+	}
+
+	/// Changes after sequence number `seq` (0 for all) and the current
+	/// sequence number to pass next time.
+	///
+	/// Only the last `MAX_CHANGES` changes are kept: if `seq` is older than
+	/// that, start over with `get_nodes()`.
+	pub fn changes_since(&self, seq: u64) -> (Vec<ContactChange>, u64) {
+		let log = self.changes.lock().unwrap();
+
+		let changes = log.changes.iter()
+			.filter(|&&(s, _)| s > seq)
+			.map(|&(_, ref c)| c.clone())
+			.collect();
+
+		(changes, log.seq)
 	}
 
 	pub fn get_closest_nodes(&self, key: &NodeId, n: usize) -> Vec<Node> {
@@ -107,6 +296,63 @@ impl KBuckets {
 		nodes
 	}
 
+	/// Follow contacts that were given by hostname to their current IP address
+	pub fn resolve_hostnames(&self) {
+		// DNS may take a while, do not hold the bucket locks meanwhile
+		let nodes:Vec<Node> = self.get_nodes().into_iter()
+			.filter(|n| n.hostname.is_some())
+			.collect();
+
+		for mut resolved in nodes.into_iter() {
+			let old_addr = resolved.addr;
+			match resolved.resolve() {
+				Ok(true) => (),
+				Ok(false) => continue,
+				Err(e) => {
+					debug!("Could not resolve {:?}: {}", resolved.hostname, e);
+					continue;
+				},
+			}
+
+			let mut bucket = match self.get_bucket(&resolved.node_id) {
+				None => continue,
+				Some(bucket) => bucket,
+			};
+			// unless it was removed in the meantime
+			if let Some(node) = bucket.iter_mut().find(|n| n.addr == old_addr && n.node_id == resolved.node_id) {
+				node.addr = resolved.addr;
+				info!("{:?} changed its address", node);
+				self.record(ContactChange::Updated(node.clone()));
+			}
+		}
+	}
+
+	/// Contacts given by hostname start with a placeholder NodeId (see
+	/// `Kademlia::add_supernode()`). Once the node at that address tells us
+	/// its real NodeId, it takes over the hostname and the placeholder goes.
+	fn adopt_hostname(&mut self, mut node: Node) -> Node {
+		if node.hostname.is_some() {
+			return node;
+		}
+
+		let placeholder = self.buckets.iter()
+			.filter_map(|b| b.lock().unwrap().iter()
+				.find(|n| n.hostname.is_some() && n.addr == node.addr && n.node_id != node.node_id)
+				.cloned())
+			.next();
+
+		if let Some(placeholder) = placeholder {
+			debug!("{:?} is the contact given as {:?}", node, placeholder.hostname);
+			node.hostname = placeholder.hostname.clone();
+			self.remove(&placeholder);
+		}
+		node
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buckets.iter().all(|b| b.lock().unwrap().is_empty())
+	}
+
 	pub fn get_nodes(&self) -> Vec<Node> {
 		let append = |a:Vec<Node>, b:MutexGuard<Vec<Node>>| {
 			let res:Vec<Node> = a.into_iter().chain(b.clone().into_iter()).collect();
@@ -119,6 +365,12 @@ impl KBuckets {
 	}
 }
 
+/// The tests add localhost contacts
+#[cfg(test)]
+fn local_buckets(own_id: NodeId, k: usize) -> KBuckets {
+	KBuckets::with_address_policy(Arc::new(Mutex::new(own_id)), k, AddressPolicy::AllowLocal, None)
+}
+
 #[test]
 fn test_get_bucket() {
 	let this = [0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,
@@ -128,7 +380,7 @@ fn test_get_bucket() {
 	let farest  = [0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,
 		               0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff];
 
-	let b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+	let b = local_buckets(this, K_PARAM);
 	assert_eq!(b.get_bucket_idx(&this), None);
 	assert_eq!(b.get_bucket_idx(&nearest), Some(0));
 	assert_eq!(b.get_bucket_idx(&farest), Some(NODEID_BYTELEN*8-1));
@@ -141,7 +393,7 @@ fn test_get_bucket() {
 #[test]
 fn test_get_nearest() {
 	let this = [0x00; NODEID_BYTELEN];
-	let mut b = KBuckets::new(Arc::new(Mutex::new(this.clone())));
+	let mut b = local_buckets(this, K_PARAM);
 
 	let mut that = this.clone();
 	that[NODEID_BYTELEN-1] = 0x01;
@@ -151,3 +403,159 @@ fn test_get_nearest() {
 	let node_list = b.get_closest_nodes(&this, 10);
 	assert_eq!(node_list, vec![n]);
 }
+
+#[test]
+fn test_is_empty() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+	assert!(b.is_empty());
+
+	let mut that = this.clone();
+	that[0] = 0x80;
+	let n = Node::new("localhost:0", that).unwrap();
+	ignore(b.add(n));
+	assert!(!b.is_empty());
+}
+
+#[test]
+fn test_k() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, 8);
+
+	// all of them go into the same bucket
+	for i in 0..10 {
+		let mut that = [0xff; NODEID_BYTELEN];
+		that[NODEID_BYTELEN-1] = i;
+		let n = Node::new(("localhost", 2000 + i as u16), that).unwrap();
+		ignore(b.add(n));
+	}
+
+	assert_eq!(b.get_nodes().len(), 8);
+	assert_eq!(b.get_closest_nodes(&this, 20).len(), 8);
+}
+
+#[test]
+fn test_changes_since() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+
+	let node = |i: u8| {
+		let mut that = [0xff; NODEID_BYTELEN];
+		that[NODEID_BYTELEN-1] = i;
+		Node::new(("localhost", 2000 + i as u16), that).unwrap()
+	};
+
+	ignore(b.add(node(1)));
+	ignore(b.add(node(2)));
+	let (changes, seq) = b.changes_since(0);
+	assert_eq!(changes, vec![ContactChange::Added(node(1)), ContactChange::Added(node(2))]);
+	assert_eq!(seq, 2);
+
+	assert!(b.replace(&node(1), node(3)));
+	assert!(!b.replace(&node(1), node(4)));
+	let (changes, next_seq) = b.changes_since(seq);
+	assert_eq!(changes, vec![ContactChange::Removed(node(1)), ContactChange::Added(node(3))]);
+	assert_eq!(next_seq, 4);
+
+	assert_eq!(b.changes_since(next_seq), (vec![], 4));
+}
+
+#[test]
+fn test_remove() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+
+	let mut that = this.clone();
+	that[0] = 0x80;
+	let n = Node::new("localhost:0", that).unwrap();
+	ignore(b.add(n.clone()));
+
+	assert!(b.remove(&n));
+	assert!(!b.remove(&n));
+	assert!(b.is_empty());
+	assert_eq!(b.changes_since(1).0, vec![ContactChange::Removed(n)]);
+}
+
+#[test]
+fn test_add_keeps_rtt() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+
+	let that = [0xff; NODEID_BYTELEN];
+	ignore(b.add(Node::new("localhost:2000", that).unwrap()));
+
+	let learned = Node::new("localhost:2000", that).unwrap();
+	learned.update_rtt(Duration::from_millis(20));
+	ignore(b.add(learned));
+
+	assert_eq!(b.get_nodes()[0].rtt(), Some(Duration::from_millis(20)));
+}
+
+#[test]
+fn test_stale_buckets() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+	let now = Instant::now();
+	let period = Duration::from_secs(60);
+	assert!(b.stale_buckets(now, period).is_empty());
+
+	for idx in vec![0, 7, 8, 100, NODEID_BYTELEN*8-1] {
+		assert_eq!(b.get_bucket_idx(&b.random_id_in_bucket(idx)), Some(idx));
+	}
+
+	let mut that = [0x00; NODEID_BYTELEN];
+	that[NODEID_BYTELEN-2] = 0x01;
+	ignore(b.add(Node::new("localhost:2000", that).unwrap()));
+	assert_eq!(b.stale_buckets(now, period), (8..NODEID_BYTELEN*8).collect::<Vec<usize>>());
+
+	b.touch(&b.random_id_in_bucket(100), now);
+	assert!(!b.stale_buckets(now + Duration::from_secs(59), period).contains(&100));
+	assert!(b.stale_buckets(now + Duration::from_secs(60), period).contains(&100));
+}
+
+#[test]
+fn test_address_policy() {
+	let own_id = Arc::new(Mutex::new([0x00; NODEID_BYTELEN]));
+	let local = "127.0.0.1:2000".parse().unwrap();
+	let mut b = KBuckets::with_address_policy(own_id.clone(), K_PARAM, AddressPolicy::AllowPrivate, Some(local));
+
+	ignore(b.add(Node::new("127.0.0.1:2001", [0x01; NODEID_BYTELEN]).unwrap()));
+	assert!(b.is_empty());
+	ignore(b.add(Node::new("192.168.1.1:2001", [0x02; NODEID_BYTELEN]).unwrap()));
+	assert_eq!(b.get_nodes().len(), 1);
+
+	let b = KBuckets::with_address_policy(own_id.clone(), K_PARAM, AddressPolicy::AllowLocal, Some(local));
+	assert!(b.accepts(&"127.0.0.1:2001".parse().unwrap()));
+	assert!(!b.accepts(&local));
+
+	let b = KBuckets::with_address_policy(own_id, K_PARAM, AddressPolicy::GlobalOnly,
+		Some("[::]:2000".parse().unwrap()));
+	assert!(!b.accepts(&"10.0.0.1:2000".parse().unwrap()));
+	assert!(!b.accepts(&"[fd00::1]:2000".parse().unwrap()));
+	assert!(b.accepts(&"8.8.8.8:2000".parse().unwrap()));
+	assert!(!b.accepts(&"[::1]:2000".parse().unwrap()));
+}
+
+#[test]
+fn test_resolve_hostnames() {
+	let this = [0x00; NODEID_BYTELEN];
+	let mut b = local_buckets(this, K_PARAM);
+
+	let mut node = Node::with_hostname("localhost:2000", [0x01; NODEID_BYTELEN]).unwrap();
+	let current = node.addr;
+	// as if the name pointed elsewhere when we added it
+	node.addr = "127.0.0.2:2000".parse().unwrap();
+	ignore(b.add(node));
+	let (_, seq) = b.changes_since(0);
+
+	b.resolve_hostnames();
+	assert_eq!(b.get_nodes()[0].addr, current);
+	assert_eq!(b.changes_since(seq).0.len(), 1);
+
+	// the real NodeId replaces the placeholder, the hostname stays
+	ignore(b.add(Node::new(current, [0x02; NODEID_BYTELEN]).unwrap()));
+	let nodes = b.get_nodes();
+	assert_eq!(nodes.len(), 1);
+	assert_eq!(nodes[0].node_id, [0x02; NODEID_BYTELEN]);
+	assert_eq!(nodes[0].hostname, Some("localhost:2000".to_string()));
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+
+use message::enc_id;
+use node::NodeId;
+
+/// A key values are stored under.
+///
+/// Values are stored on the nodes whose NodeIds are closest to the key,
+/// so both share the same id space. They are still distinct types to
+/// keep "where to store" and "who to talk to" apart.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(NodeId);
+
+impl Key {
+	pub fn new(bytes: NodeId) -> Key {
+		Key(bytes)
+	}
+
+	/// The point in the id space to look up to find the value
+	pub fn id(&self) -> NodeId {
+		self.0
+	}
+}
+
+impl From<NodeId> for Key {
+	fn from(bytes: NodeId) -> Key {
+		Key(bytes)
+	}
+}
+
+impl fmt::Debug for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Key({})", enc_id(&self.0))
+	}
+}
+
+impl fmt::Display for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", enc_id(&self.0))
+	}
+}
@@ -3,17 +3,21 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_bytes;
 extern crate bincode;
+extern crate serde_bencode;
 
 extern crate rand;
 #[macro_use] extern crate log;
 extern crate env_logger;
 extern crate crypto;
 extern crate docopt;
+extern crate net2;
+extern crate get_if_addrs;
 
 extern crate rustc_serialize;
 
-use bincode::{serialize, deserialize, Bounded};
+use bincode::deserialize;
 
 extern crate futures_await as futures;
 extern crate tokio_core;
@@ -25,9 +29,18 @@ extern crate dbus;
 
 #[macro_use]
 mod node;
+mod key;
 mod utils;
 mod server;
 mod message;
+mod codec;
+mod clock;
+mod config;
+mod chunks;
+mod trace;
+mod error_log;
+mod cost;
+mod signing;
 mod kademlia;
 mod kbuckets;
 mod closest_nodes_iter;
@@ -40,15 +53,16 @@ mod dbus_service;
 mod test;
 
 use std::env;
+use std::process;
 use std::thread::{spawn,sleep};
 use std::fs::File;
 use std::path::{PathBuf,Path};
-use std::io::{Write,Read};
+use std::io::Read;
 use std::net::SocketAddr;
-use std::time::Duration;
 
 use docopt::Docopt;
 
+use clock::Duration;
 use kademlia::Kademlia;
 use node::Node;
 
@@ -130,7 +144,11 @@ fn main() {
 	let core = Core::new().unwrap();
 	let handle = core.handle();
 
-	let kad = Kademlia::bootstrap(handle, &listen_addr[..], supernodes, None);
+	let kad = Kademlia::bootstrap(handle, &listen_addr[..], supernodes, None)
+		.unwrap_or_else(|e| {
+			error!("Cannot join the network on {}: {:?}", listen_addr, e);
+			process::exit(1)
+		});
 
 	let this = kad.clone();
 	handle.spawn_fn(|| {
@@ -139,11 +157,8 @@ fn main() {
 	});
 
 	let future = Interval::new(Duration::from_secs(5*60), &handle).unwrap().for_each(move |_| {
-		let nodes = kad.get_nodes();
-		let contents = serialize(&nodes, Bounded(100*1024)).unwrap_or(Vec::new());
-
-		if let Ok(mut cfg_file) = File::create(&cfg_path) {
-			cfg_file.write(&contents[..]).unwrap_or(0);
+		if let Err(e) = kad.save_routing_table(&cfg_path) {
+			warn!("Cannot save the routing table: {}", e);
 		}
 
 		Ok(())
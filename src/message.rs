@@ -1,12 +1,138 @@
 use std::fmt;
 use std::ops::Deref;
 
+use rand::{OsRng, Rng};
+use serde_bytes;
+
+use clock::Duration;
 use node::{Node, NodeId};
+use signing::PublicKey;
 
 pub const COOKIE_BYTELEN:usize = 160/8;
 
 pub type Cookie = [u8; COOKIE_BYTELEN];
 
+/// A random cookie from the OS's CSPRNG. Responses are matched to our
+/// requests by (address, cookie), so guessable cookies would allow
+/// spoofed responses.
+pub fn generate_cookie() -> Cookie {
+	let mut cookie = [0u8; COOKIE_BYTELEN];
+	OsRng::new().expect("no OS random number generator").fill_bytes(&mut cookie);
+	cookie
+}
+
+/// Maximum number of keys in a single FindValues request
+pub const MAX_BATCH_KEYS: usize = 64;
+/// Split FoundValues responses into datagrams of roughly this size
+pub const MAX_BATCH_RESPONSE_LEN: usize = 1400;
+/// All FoundValues datagrams answering one FindValues are at most about
+/// this large, so a small request cannot make us send megabytes to a
+/// spoofed address
+pub const MAX_BATCH_TOTAL_LEN: usize = 4 * MAX_BATCH_RESPONSE_LEN;
+/// How often a Store may be forwarded (see `FarStorePolicy::Forward`)
+pub const MAX_STORE_HOPS: u8 = 2;
+
+/// Version of the wire protocol. Every datagram starts with it (since
+/// version 2, see `codec::add_version()`), a peer also tells it in `HelloAck`.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Datagrams without a version prefix. Their messages have another
+/// layout, we drop them.
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+/// Oldest version we can still talk to
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Can we decode messages of a peer speaking `version`?
+pub fn is_compatible(version: u8) -> bool {
+	version >= MIN_PROTOCOL_VERSION && version <= PROTOCOL_VERSION
+}
+
+/// Optional features a node announces in `HelloAck.features`
+pub const FEATURE_SIGNING: u32 = 1 << 0;
+pub const FEATURE_COMPRESSION: u32 = 1 << 1;
+pub const FEATURE_BATCHING: u32 = 1 << 2;
+/// What this implementation supports
+pub const OWN_FEATURES: u32 = FEATURE_SIGNING | FEATURE_BATCHING;
+
+/// (De)serializes NodeIds and cookies as byte strings instead of
+/// sequences of integers in bencoded messages, like the ids of the
+/// mainline DHT. Otherwise they stay fixed arrays.
+pub mod id_bytes {
+	use serde::{Serializer, Deserializer, Serialize, Deserialize};
+	use serde::de::Error;
+	use serde_bytes::ByteBuf;
+
+	use codec;
+	use node::{NodeId, NODEID_BYTELEN};
+
+	pub fn serialize<S: Serializer>(id: &NodeId, serializer: S) -> Result<S::Ok, S::Error> {
+		if codec::byte_ids() {
+			serializer.serialize_bytes(&id[..])
+		} else {
+			id.serialize(serializer)
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NodeId, D::Error> {
+		if !codec::byte_ids() {
+			return <NodeId as Deserialize>::deserialize(deserializer);
+		}
+
+		let buf = try!(ByteBuf::deserialize(deserializer));
+		if buf.len() != NODEID_BYTELEN {
+			return Err(D::Error::invalid_length(buf.len(), &"a 20 byte id"));
+		}
+
+		let mut id = [0u8; NODEID_BYTELEN];
+		id.copy_from_slice(&buf[..]);
+		Ok(id)
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdBytes(#[serde(with = "id_bytes")] NodeId);
+
+/// Like `id_bytes`, for `FindValues.keys`
+mod id_list_bytes {
+	use serde::{Serializer, Deserializer, Serialize, Deserialize};
+
+	use node::NodeId;
+	use super::IdBytes;
+
+	pub fn serialize<S: Serializer>(ids: &Vec<NodeId>, serializer: S) -> Result<S::Ok, S::Error> {
+		let ids:Vec<IdBytes> = ids.iter().map(|id| IdBytes(*id)).collect();
+		ids.serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<NodeId>, D::Error> {
+		let ids:Vec<IdBytes> = try!(Vec::deserialize(deserializer));
+		Ok(ids.into_iter().map(|id| id.0).collect())
+	}
+}
+
+/// Like `id_bytes`, for `FoundValues.entries`
+mod entry_list_bytes {
+	use serde::{Serializer, Deserializer, Serialize, Deserialize};
+
+	use node::NodeId;
+	use super::{IdBytes, Value, RecordSignature};
+
+	pub fn serialize<S: Serializer>(entries: &Vec<(NodeId, Value, Option<RecordSignature>)>, serializer: S)
+		-> Result<S::Ok, S::Error>
+	{
+		let entries:Vec<(IdBytes, &Value, &Option<RecordSignature>)> = entries.iter()
+			.map(|&(id, ref v, ref sig)| (IdBytes(id), v, sig))
+			.collect();
+		entries.serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D)
+		-> Result<Vec<(NodeId, Value, Option<RecordSignature>)>, D::Error>
+	{
+		let entries:Vec<(IdBytes, Value, Option<RecordSignature>)> = try!(Vec::deserialize(deserializer));
+		Ok(entries.into_iter().map(|(id, v, sig)| (id.0, v, sig)).collect())
+	}
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum Message {
 		Ping(Ping),
@@ -16,7 +142,13 @@ pub enum Message {
 		FindValue(FindValue),
 		FoundValue(FoundValue),
 		Store(Store),
+		StoreResponse(StoreResponse),
 		Listen(Listen),
+		FindValues(FindValues),
+		FoundValues(FoundValues),
+		Hello(Hello),
+		HelloAck(HelloAck),
+		Delete(Delete),
 		Timeout,
 }
 
@@ -30,7 +162,13 @@ impl Message {
 			Message::FindValue(ref r) => Some(&r.cookie),
 			Message::FoundValue(ref r) => Some(&r.cookie),
 			Message::Store(ref r) => Some(&r.cookie),
+			Message::StoreResponse(ref r) => Some(&r.cookie),
             Message::Listen(ref r) => Some(&r.cookie),
+			Message::FindValues(ref r) => Some(&r.cookie),
+			Message::FoundValues(ref r) => Some(&r.cookie),
+			Message::Hello(ref r) => Some(&r.cookie),
+			Message::HelloAck(ref r) => Some(&r.cookie),
+			Message::Delete(ref r) => Some(&r.cookie),
 			Message::Timeout => None,
 		}
 	}
@@ -44,7 +182,13 @@ impl Message {
 			Message::FindValue(ref r) => Some(r.sender_id.clone()),
 			Message::FoundValue(ref r) => Some(r.sender_id.clone()),
 			Message::Store(ref r) => Some(r.sender_id.clone()),
+			Message::StoreResponse(ref r) => Some(r.sender_id.clone()),
 			Message::Listen(ref r) => Some(r.sender_id.clone()),
+			Message::FindValues(ref r) => Some(r.sender_id.clone()),
+			Message::FoundValues(ref r) => Some(r.sender_id.clone()),
+			Message::Hello(ref r) => Some(r.sender_id.clone()),
+			Message::HelloAck(ref r) => Some(r.sender_id.clone()),
+			Message::Delete(ref r) => Some(r.sender_id.clone()),
 			Message::Timeout => None,
 		}
 	}
@@ -52,40 +196,96 @@ impl Message {
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Ping {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie: Cookie,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Pong {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie: Cookie,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FindNode {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:    Cookie,
+	#[serde(with = "id_bytes")]
 	pub key:       NodeId,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FindValue {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:    Cookie,
+	#[serde(with = "id_bytes")]
 	pub key:       NodeId,
+	/// proves we may read values with an ACL
+	pub proof:     Option<AccessProof>,
+}
+
+/// Signature of `access_proof_msg(cookie, key, recipient)` by `public_key`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct AccessProof {
+	pub public_key: PublicKey,
+	#[serde(with = "serde_bytes")]
+	pub signature:  Vec<u8>,
+}
+
+/// The NodeId of the recipient is signed, too: a holder that got our
+/// proof cannot pass it on to other holders to read values as us
+pub fn access_proof_msg(cookie: &Cookie, key: &NodeId, recipient: &NodeId) -> Vec<u8> {
+	cookie.iter().chain(key.iter()).chain(recipient.iter()).cloned().collect()
+}
+
+/// Signature of `record_msg(key, value)` by the publisher of a value
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct RecordSignature {
+	pub public_key: PublicKey,
+	#[serde(with = "serde_bytes")]
+	pub signature:  Vec<u8>,
+}
+
+pub fn record_msg(key: &NodeId, value: &[u8]) -> Vec<u8> {
+	key.iter().chain(value.iter()).cloned().collect()
+}
+
+/// What `Delete.signature` signs. Tagged, so the signature of a record
+/// cannot be passed off as one of its deletion.
+pub fn delete_msg(key: &NodeId, value: &[u8]) -> Vec<u8> {
+	b"delete:".iter().chain(key.iter()).chain(value.iter()).cloned().collect()
+}
+
+/// `ttl` in milliseconds, rounded up (so only a zero TTL becomes 0) and
+/// capped at u32::MAX (about 49 days)
+pub fn ttl_ms(ttl: Duration) -> u32 {
+	let ms = ttl.as_secs().saturating_mul(1000) + (ttl.subsec_nanos() as u64 + 999_999) / 1_000_000;
+	if ms > u32::max_value() as u64 { u32::max_value() } else { ms as u32 }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Listen {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:    Cookie,
+	#[serde(with = "id_bytes")]
 	pub key:       NodeId,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FoundNode {
+	#[serde(with = "id_bytes")]
 	pub sender_id:  NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:     Cookie,
 	pub node_count: usize,
 	pub node:       Node,
@@ -93,22 +293,117 @@ pub struct FoundNode {
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct FoundValue {
+	#[serde(with = "id_bytes")]
 	pub sender_id:   NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:      Cookie,
 	pub value_count: usize,
 	pub value:       Value,
+	/// the publisher's signature, so requesters need not trust us
+	pub signature:   Option<RecordSignature>,
+}
+
+/// Ask for the values of several keys at once
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct FindValues {
+	#[serde(with = "id_bytes")]
+	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:    Cookie,
+	#[serde(with = "id_list_bytes")]
+	pub keys:      Vec<NodeId>,
+}
+
+/// Response to FindValues, possibly split over several datagrams.
+/// `entry_count` is the number of entries in all of them, each comes
+/// with its publisher's signature (if any, see `Kademlia::put_signed()`).
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct FoundValues {
+	#[serde(with = "id_bytes")]
+	pub sender_id:   NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:      Cookie,
+	pub entry_count: usize,
+	#[serde(with = "entry_list_bytes")]
+	pub entries:     Vec<(NodeId, Value, Option<RecordSignature>)>,
+}
+
+/// Ask a node for its version and features
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Hello {
+	#[serde(with = "id_bytes")]
+	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:    Cookie,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct HelloAck {
+	#[serde(with = "id_bytes")]
+	pub sender_id:        NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:           Cookie,
+	/// crate version of the implementation
+	pub version:          String,
+	pub protocol_version: u8,
+	/// `FEATURE_*` flags
+	pub features:         u32,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Store {
+	#[serde(with = "id_bytes")]
 	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
 	pub cookie:    Cookie,
+	#[serde(with = "id_bytes")]
 	pub key:       NodeId,
 	pub value:     Value,
+	/// if set, only these public keys may read the value
+	pub acl:       Option<Vec<PublicKey>>,
+	/// how often the Store may still be forwarded
+	pub hops_left: u8,
+	/// remove the values the sender stored for `key` first
+	pub replace:   bool,
+	/// only store it if the receiver holds no value of others for `key`
+	pub if_absent: bool,
+	/// remaining lifetime of the value (receivers cap it at their own TTL),
+	/// see `ttl_ms()`
+	pub ttl_ms:    u32,
+	/// see `Kademlia::put_signed()`
+	pub signature: Option<RecordSignature>,
+}
+
+/// Remove a value the sender stored and keep it from being stored again
+/// for `ttl_ms` (e.g. by a republish that crossed the Delete)
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Delete {
+	#[serde(with = "id_bytes")]
+	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:    Cookie,
+	#[serde(with = "id_bytes")]
+	pub key:       NodeId,
+	pub value:     Value,
+	pub ttl_ms:    u32,
+	/// required if the value is signed, see `Kademlia::delete_signed()`
+	pub signature: Option<RecordSignature>,
+}
+
+/// Tells the publisher whether we stored the value of a `Store` (or
+/// applied a `Delete`)
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct StoreResponse {
+	#[serde(with = "id_bytes")]
+	pub sender_id: NodeId,
+	#[serde(with = "id_bytes")]
+	pub cookie:    Cookie,
+	pub accepted:  bool,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Hash)]
 pub struct Value {
+	#[serde(with = "serde_bytes")]
 	pub data: Vec<u8>
 }
 
@@ -138,8 +433,24 @@ pub fn enc_vec(id: &Vec<u8>) -> String {
 
 impl fmt::Debug for Store {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "sender={}, cookie={}, key: {}, value_len: {}",
-			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), &self.value.data.len())
+		write!(f, "sender={}, cookie={}, key: {}, value_len: {}, hops_left: {}, ttl_ms: {}, signed: {}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), &self.value.data.len(),
+			self.hops_left, self.ttl_ms, self.signature.is_some())
+	}
+}
+
+impl fmt::Debug for Delete {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, key: {}, value_len: {}, ttl_ms: {}, signed: {}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), enc_id(&self.key), &self.value.data.len(),
+			self.ttl_ms, self.signature.is_some())
+	}
+}
+
+impl fmt::Debug for StoreResponse {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, accepted={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.accepted)
 	}
 }
 
@@ -192,3 +503,32 @@ impl fmt::Debug for Listen {
 	}
 }
 
+
+impl fmt::Debug for FindValues {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, keys={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.keys.len())
+	}
+}
+
+impl fmt::Debug for FoundValues {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, count={} entries={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.entry_count, self.entries.len())
+	}
+}
+
+impl fmt::Debug for Hello {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}",
+			enc_id(&self.sender_id), enc_id(&self.cookie))
+	}
+}
+
+impl fmt::Debug for HelloAck {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sender={}, cookie={}, version={}, protocol={}, features={:#x}",
+			enc_id(&self.sender_id), enc_id(&self.cookie), self.version,
+			self.protocol_version, self.features)
+	}
+}
@@ -1,15 +1,16 @@
 use std::io;
 use std::fmt;
-use std::time::Instant;
+use std::cmp;
 use std::sync::{Arc,Mutex};
 use std::net::{SocketAddr,ToSocketAddrs};
 
-#[cfg(not(test))]
-use std::net::{SocketAddrV4,SocketAddrV6};
 
-use rand;
+use rand::{OsRng, Rng};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use clock::{self, Duration, Instant, SharedClock};
 use utils;
-use message::enc_id;
+use message::{self, enc_id};
 
 pub const NODEID_BYTELEN:usize = 160/8;
 
@@ -24,122 +25,334 @@ macro_rules! desc_dist_order {
 }
 
 fn now_mutex() -> Arc<Mutex<Instant>> {
-	Arc::new(Mutex::new(Instant::now()))
+	Arc::new(Mutex::new(clock::system().now()))
 }
 
+/// `now - then`, but zero if `then` is later (another thread may have
+/// updated `then` after we took `now`)
+fn since(now: Instant, then: Instant) -> Duration {
+	if now > then {
+		now - then
+	} else {
+		Duration::from_secs(0)
+	}
+}
+
+/// Round trip time estimation like TCP's (RFC 6298)
+#[derive(Clone, Copy, Debug)]
+pub struct RttEstimate {
+	pub srtt:   Duration,
+	pub rttvar: Duration,
+}
+
+fn no_rtt() -> Arc<Mutex<Option<RttEstimate>>> {
+	Arc::new(Mutex::new(None))
+}
+
+fn zero_mutex() -> Arc<Mutex<usize>> {
+	Arc::new(Mutex::new(0))
+}
+
+/// How we heard from a contact
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Reachability {
+	/// it sent us requests on its own
+	pub inbound:   bool,
+	/// it answered one of our requests
+	pub responded: bool,
+}
+
+fn unknown_reachability() -> Arc<Mutex<Reachability>> {
+	Arc::new(Mutex::new(Reachability::default()))
+}
+
+fn unknown_version() -> Arc<Mutex<Option<u8>>> {
+	Arc::new(Mutex::new(None))
+}
+
+/// RTT assumed for contacts we never measured
+const UNKNOWN_RTT_MS: f64 = 1000.0;
+/// Bounds for adaptive timeouts
+const MIN_TIMEOUT_MS: u32 = 200;
+const MAX_TIMEOUT_FACTOR: u32 = 10;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Node {
 	pub addr:      SocketAddr,
+	#[serde(with = "message::id_bytes")]
 	pub node_id:   NodeId,
 	#[serde(skip_serializing)]
 	#[serde(skip_deserializing,default="now_mutex")]
 	pub last_seen: Arc<Mutex<Instant>>,
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="no_rtt")]
+	pub rtt:       Arc<Mutex<Option<RttEstimate>>>,
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="now_mutex")]
+	pub first_seen: Arc<Mutex<Instant>>,
+	/// requests in a row that timed out
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="zero_mutex")]
+	pub failures:  Arc<Mutex<usize>>,
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="unknown_reachability")]
+	pub reachability: Arc<Mutex<Reachability>>,
+	/// protocol version of its last message
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="unknown_version")]
+	pub protocol_version: Arc<Mutex<Option<u8>>>,
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing)]
+	pub hostname:  Option<String>,
+	/// where `last_seen` and `first_seen` start from
+	#[serde(skip_serializing)]
+	#[serde(skip_deserializing,default="clock::system")]
+	clock:         SharedClock,
 }
 
 impl Node {
 	pub fn new<A: ToSocketAddrs>(addr: A, node_id: NodeId) -> io::Result<Node> {
+		Self::with_clock(addr, node_id, clock::system())
+	}
+
+	/// Like `new()`, but the contact is first and last seen at `clock.now()`
+	/// (e.g. of a `MockClock`)
+	pub fn with_clock<A: ToSocketAddrs>(addr: A, node_id: NodeId, clock: SharedClock) -> io::Result<Node> {
 		let mut it = try!(addr.to_socket_addrs());
 
 		let err = io::Error::new(io::ErrorKind::Other, "no valid IP address");
 		let addr = try!(it.next().ok_or(err));
 		let addr = utils::ip4or6(addr);
 
+		if Self::is_link_local(&addr) {
+			let err = io::Error::new(io::ErrorKind::Other,
+				"link-local IPv6 addresses are not supported (their scope id is lost on the wire)");
+			return Err(err);
+		}
+
 		if !Self::is_address_valid(&addr) {
 			let err = io::Error::new(io::ErrorKind::Other, "no valid IP address");
 			return Err(err);
 		}
 
+		let now = clock.now();
 		let node = Node {
 			addr:      addr,
 			node_id:   node_id,
-			last_seen: Arc::new(Mutex::new(Instant::now())),
+			last_seen: Arc::new(Mutex::new(now)),
+			rtt:       no_rtt(),
+			first_seen: Arc::new(Mutex::new(now)),
+			failures:  zero_mutex(),
+			reachability: unknown_reachability(),
+			protocol_version: unknown_version(),
+			hostname:  None,
+			clock:     clock,
 		};
 
 		Ok(node)
 	}
 
+	/// Like `new()`, but remembers `hostname` (e.g. "example.org:56789") so
+	/// `resolve()` can follow it when its IP address changes (dynamic DNS).
+	pub fn with_hostname(hostname: &str, node_id: NodeId) -> io::Result<Node> {
+		let mut node = try!(Node::new(hostname, node_id));
+		node.hostname = Some(hostname.to_string());
+		Ok(node)
+	}
+
+	/// Resolves the hostname again (if there is one).
+	/// Returns true if the address changed.
+	pub fn resolve(&mut self) -> io::Result<bool> {
+		let hostname = match self.hostname {
+			None => return Ok(false),
+			Some(ref hostname) => hostname.clone(),
+		};
+
+		let addr = try!(Node::new(&hostname[..], self.node_id)).addr;
+		let changed = addr != self.addr;
+		self.addr = addr;
+
+		Ok(changed)
+	}
+
+	/// A random NodeId from the OS's CSPRNG: if others could predict our
+	/// id, they could place themselves next to it (eclipse attack)
 	pub fn generate_id() -> NodeId {
 		let mut id = [0u8; NODEID_BYTELEN];
-		for i in id.iter_mut() {
-			*i = rand::random::<u8>();
+		OsRng::new().expect("no OS random number generator").fill_bytes(&mut id);
+		id
+	}
+
+	/// Derive a key from several components, e.g. `[namespace, type, id]`.
+	///
+	/// Each component is prefixed by its length (8 bytes, big endian), so
+	/// `["ab", "c"]` and `["a", "bc"]` yield different ids.
+	pub fn id_from_components(components: &[&[u8]]) -> NodeId {
+		let mut hasher = Sha1::new();
+
+		for c in components.iter() {
+			let len = c.len() as u64;
+			let len_bytes:Vec<u8> = (0..8).rev().map(|i| (len >> (8*i)) as u8).collect();
+
+			hasher.input(&len_bytes[..]);
+			hasher.input(c);
 		}
+
+		let mut output = vec![0x0; hasher.output_bytes()];
+		hasher.result(&mut output[..]);
+
+		let mut id = [0u8; NODEID_BYTELEN];
+		id.clone_from_slice(&output[..NODEID_BYTELEN]);
 		id
 	}
 
-	pub fn update_last_seen(&mut self) {
+	pub fn update_last_seen(&mut self, now: Instant) {
 		let mut last_seen = self.last_seen.lock().unwrap();
-		*last_seen = Instant::now();
+		*last_seen = now;
 	}
 
-	/// TODO: replace by rust stdlib methods, as soon as they become stable
-	#[cfg(not(test))]
-	fn is_address_valid(addr: &SocketAddr) -> bool {
-		match addr {
-			&SocketAddr::V4(ref ip) => Self::is_ipv4_global(ip),
-			&SocketAddr::V6(ref ip) => Self::is_ipv6_global(ip),
+	/// How long we have not heard from the contact
+	pub fn unseen_for(&self, now: Instant) -> Duration {
+		since(now, *self.last_seen.lock().unwrap())
+	}
+
+	/// Smoothed round trip time (if we ever got a response)
+	pub fn rtt(&self) -> Option<Duration> {
+		self.rtt.lock().unwrap().map(|e| e.srtt)
+	}
+
+	/// How long to wait for a response of this node: srtt + 4*rttvar
+	/// (like TCP's RTO) or `default_ms` if we never measured its RTT
+	pub fn timeout_ms(&self, default_ms: u32) -> u32 {
+		match *self.rtt.lock().unwrap() {
+			None => default_ms,
+			Some(e) => {
+				let rto = e.srtt + e.rttvar*4;
+				let rto_ms = rto.as_secs() as u32 * 1000 + rto.subsec_nanos() / 1_000_000;
+				cmp::max(MIN_TIMEOUT_MS, cmp::min(rto_ms, default_ms*MAX_TIMEOUT_FACTOR))
+			}
 		}
 	}
 
-	#[cfg(test)]
-	fn is_address_valid(_addr: &SocketAddr) -> bool {
-		true
+	pub fn record_failure(&self) {
+		*self.failures.lock().unwrap() += 1;
 	}
 
-	#[cfg(not(test))]
-	fn is_ipv4_global(addr: &SocketAddrV4) -> bool {
-		let ip = addr.ip();
+	pub fn reset_failures(&self) {
+		*self.failures.lock().unwrap() = 0;
+	}
 
-		let is_private = match (ip.octets()[0], ip.octets()[1]) {
-			(10, _) => true,
-			(172, b) if b >= 16 && b <= 31 => true,
-			(192, 168) => true,
-			_ => false
-		};
-		let is_loopback = ip.octets()[0] == 127;
+	/// The contact answered one of our requests
+	pub fn mark_responded(&self) {
+		self.reachability.lock().unwrap().responded = true;
+	}
 
-		let is_link_local = ip.octets()[0] == 169 && ip.octets()[1] == 254;
+	/// The contact sent us a request
+	pub fn mark_inbound(&self) {
+		self.reachability.lock().unwrap().inbound = true;
+	}
 
-		let is_broadcast = ip.octets()[0] == 255 && ip.octets()[1] == 255 &&
-			ip.octets()[2] == 255 && ip.octets()[3] == 255;
+	/// The protocol version it speaks, None until we got a message
+	pub fn protocol_version(&self) -> Option<u8> {
+		*self.protocol_version.lock().unwrap()
+	}
+
+	pub fn set_protocol_version(&self, version: u8) {
+		*self.protocol_version.lock().unwrap() = Some(version);
+	}
 
-        let is_documentation = match(ip.octets()[0], ip.octets()[1], ip.octets()[2], ip.octets()[3]) {
-            (192, _, 2, _) => true,
-            (198, 51, 100, _) => true,
-            (203, _, 113, _) => true,
-            _ => false
-        };
+	/// The contact talks to us, but never answered our requests
+	/// (probably behind a NAT), so others can not reach it either
+	pub fn is_inbound_only(&self) -> bool {
+		let reachability = self.reachability.lock().unwrap();
+		reachability.inbound && !reachability.responded
+	}
+
+	/// How much we want to keep this contact (higher is better): long uptime,
+	/// no failed requests and a low RTT are good.
+	pub fn quality(&self, now: Instant) -> f64 {
+		let uptime_min = since(now, *self.first_seen.lock().unwrap()).as_secs() as f64 / 60.0;
+		let failures = *self.failures.lock().unwrap() as f64;
+		let rtt_ms = self.rtt()
+			.map(|d| d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6)
+			.unwrap_or(UNKNOWN_RTT_MS);
+
+		(1.0 + uptime_min).ln() - 2.0*failures - rtt_ms/500.0
+	}
 
+	/// Take over the RTT estimate of `other` (another instance of the same
+	/// contact, e.g. one learned during a lookup) unless we measured it ourselves
+	pub fn adopt_rtt(&self, other: &Node) {
+		if Arc::ptr_eq(&self.rtt, &other.rtt) {
+			return;
+		}
 
-		!is_private && !is_loopback && !is_link_local &&
-			!is_broadcast && !is_documentation
+		let estimate = *other.rtt.lock().unwrap();
+		let mut rtt = self.rtt.lock().unwrap();
+		if rtt.is_none() {
+			*rtt = estimate;
+		}
 	}
 
-	#[cfg(not(test))]
-	fn is_ipv6_global(addr: &SocketAddrV6) -> bool {
-		let ip = addr.ip();
+	/// Feed a new round trip time measurement into the EWMAs
+	/// (alpha=1/8, beta=1/4, like TCP)
+	pub fn update_rtt(&self, sample: Duration) {
+		let mut rtt = self.rtt.lock().unwrap();
+		*rtt = Some(match *rtt {
+			None => RttEstimate {
+				srtt:   sample,
+				rttvar: sample/2,
+			},
+			Some(e) => {
+				let deviation = if e.srtt > sample { e.srtt - sample } else { sample - e.srtt };
+				RttEstimate {
+					srtt:   (e.srtt*7 + sample)/8,
+					rttvar: (e.rttvar*3 + deviation)/4,
+				}
+			}
+		});
+	}
 
-		let is_multicast = (ip.segments()[0] & 0xff00) == 0xff00;
-		let is_loopback = ip.segments() == [0, 0, 0, 0, 0, 0, 0, 1];
-		let is_unicast_link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
-		let is_unicast_site_local = (ip.segments()[0] & 0xffc0) == 0xfec0;
-		let is_unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+	/// Link-local IPv6 addresses are only usable with their scope id, but the
+	/// wire format (like serde's SocketAddrV6) does not carry it, so we never
+	/// accept such contacts.
+	pub fn is_link_local(addr: &SocketAddr) -> bool {
+		match addr {
+			&SocketAddr::V4(_) => false,
+			&SocketAddr::V6(ref addr) => (addr.ip().segments()[0] & 0xffc0) == 0xfe80,
+		}
+	}
 
-		let is_unicast_global = !is_multicast
-			&& !is_loopback && !is_unicast_link_local
-			&& !is_unicast_site_local && !is_unique_local;
+	/// Addresses nobody can be reached at (link-local ones aside), see
+	/// `config::AddressPolicy` for the ones we may not want to reach
+	fn is_address_valid(addr: &SocketAddr) -> bool {
+		match addr {
+			&SocketAddr::V4(ref addr) => {
+				let ip = addr.ip();
+				!ip.is_unspecified() && !ip.is_broadcast() && !ip.is_link_local()
+					&& !ip.is_documentation() && !ip.is_multicast()
+			},
+			&SocketAddr::V6(ref addr) => {
+				let ip = addr.ip();
+				let is_multicast_scope_global = ip.is_multicast() && (ip.segments()[0] & 0x000f) == 14;
+				!ip.is_unspecified() && (!ip.is_multicast() || is_multicast_scope_global)
+			},
+		}
+	}
 
-		let is_multicast_scope_global = if is_multicast {
-            match ip.segments()[0] & 0x000f {
-                14 => true,
-                _ => false,
-            }
-        } else {
-            false
-        };
+	/// 127.0.0.0/8 and ::1
+	pub fn is_loopback(addr: &SocketAddr) -> bool {
+		addr.ip().is_loopback()
+	}
 
-		match is_multicast_scope_global {
-			true => true,
-			false => is_unicast_global,
+	/// RFC 1918 addresses, unique local (fc00::/7) and site-local IPv6 addresses
+	pub fn is_private(addr: &SocketAddr) -> bool {
+		match addr {
+			&SocketAddr::V4(ref addr) => addr.ip().is_private(),
+			&SocketAddr::V6(ref addr) => {
+				let first = addr.ip().segments()[0];
+				(first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfec0
+			},
 		}
 	}
 }
@@ -159,17 +372,37 @@ pub fn xor(a: &NodeId, b: &NodeId) -> NodeId {
 	dist
 }
 
+/// XOR distance between two ids, printed in hex
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct Distance(pub NodeId);
+
+impl fmt::Display for Distance {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for b in self.0.iter() {
+			try!(write!(f, "{:02x}", b));
+		}
+		Ok(())
+	}
+}
+
 impl Node {
 	pub fn dist(&self, id: &NodeId) -> NodeId {
 		xor(&self.node_id, id)
 	}
+
+	pub fn distance(&self, id: &NodeId) -> Distance {
+		Distance(self.dist(id))
+	}
 }
 
 impl fmt::Debug for Node {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-	    let secs = self.last_seen.lock().unwrap().elapsed().as_secs() as f64;
-		write!(f, "Node {{ {}, id={}, last_seen={:.*}min ago }}",
-			self.addr, enc_id(&self.node_id), 2, secs/60.0)
+	    let secs = self.unseen_for(self.clock.now()).as_secs() as f64;
+		let version = self.protocol_version()
+			.map(|v| v.to_string())
+			.unwrap_or("?".to_string());
+		write!(f, "Node {{ {}, id={}, version={}, last_seen={:.*}min ago }}",
+			self.addr, enc_id(&self.node_id), version, 2, secs/60.0)
 	}
 }
 
@@ -202,6 +435,96 @@ fn dist() {
 		     0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x00,0x01])
 }
 
+#[test]
+fn id_from_components() {
+	let id1 = Node::id_from_components(&[b"namespace", b"type", b"id"]);
+	let id2 = Node::id_from_components(&[b"namespace", b"type", b"id"]);
+	assert_eq!(id1, id2);
+
+	let id1 = Node::id_from_components(&[b"ab", b"c"]);
+	let id2 = Node::id_from_components(&[b"a", b"bc"]);
+	assert!(id1 != id2);
+}
+
+#[test]
+fn resolve() {
+	let mut node = Node::with_hostname("localhost:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert_eq!(node.addr.port(), 2134);
+	assert_eq!(node.resolve().unwrap(), false);
+
+	let mut node = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert_eq!(node.resolve().unwrap(), false);
+}
+
+#[test]
+fn rtt() {
+	let node = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert_eq!(node.rtt(), None);
+
+	node.update_rtt(Duration::from_millis(800));
+	assert_eq!(node.rtt(), Some(Duration::from_millis(800)));
+
+	for _ in 0..50 {
+		node.update_rtt(Duration::from_millis(100));
+	}
+	let rtt = node.rtt().unwrap();
+	assert!(rtt >= Duration::from_millis(100) && rtt < Duration::from_millis(105));
+}
+
+#[test]
+fn link_local() {
+	assert!(Node::new("[fe80::1%2]:2134", [0x00; NODEID_BYTELEN]).is_err());
+	assert!(Node::new("[::1]:2134", [0x00; NODEID_BYTELEN]).is_ok());
+}
+
+#[test]
+fn timeout() {
+	let default_ms = 2000;
+
+	let unknown = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert_eq!(unknown.timeout_ms(default_ms), default_ms);
+
+	let fast = Node::new("127.0.0.1:2135", [0x01; NODEID_BYTELEN]).unwrap();
+	let slow = Node::new("127.0.0.1:2136", [0x02; NODEID_BYTELEN]).unwrap();
+	for _ in 0..50 {
+		fast.update_rtt(Duration::from_millis(10));
+		slow.update_rtt(Duration::from_millis(3000));
+	}
+
+	assert_eq!(fast.timeout_ms(default_ms), MIN_TIMEOUT_MS);
+	assert!(slow.timeout_ms(default_ms) >= 3000);
+	assert!(slow.timeout_ms(default_ms) > default_ms);
+}
+
+#[test]
+fn quality() {
+	let stable = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	stable.update_rtt(Duration::from_millis(20));
+
+	let flaky = Node::new("127.0.0.1:2135", [0x01; NODEID_BYTELEN]).unwrap();
+	flaky.update_rtt(Duration::from_millis(400));
+	flaky.record_failure();
+	flaky.record_failure();
+
+	let now = Instant::now();
+	assert!(stable.quality(now) > flaky.quality(now));
+
+	flaky.reset_failures();
+	assert!(stable.quality(now) > flaky.quality(now));
+}
+
+#[test]
+fn distance() {
+	let mut id = [0x00; NODEID_BYTELEN];
+	id[NODEID_BYTELEN-1] = 0x0f;
+	let node = Node::new("127.0.0.1:2134", id).unwrap();
+
+	let dist = node.distance(&[0xff; NODEID_BYTELEN]);
+	assert_eq!(dist, Distance(xor(&id, &[0xff; NODEID_BYTELEN])));
+	assert_eq!(format!("{}", dist), "ff".repeat(NODEID_BYTELEN-1) + "f0");
+	assert!(node.distance(&id) < dist);
+}
+
 #[test]
 fn asc_order() {
 	let id0xff = [0xff; NODEID_BYTELEN];
@@ -218,3 +541,29 @@ fn asc_order() {
 	nodes.sort_by(desc_dist_order!(id0x00));
 	assert_eq!(nodes, vec![node0xff, node0x00]);
 }
+
+#[test]
+fn inbound_only() {
+	let node = Node::new("127.0.0.1:2134", [0x00; NODEID_BYTELEN]).unwrap();
+	assert!(!node.is_inbound_only());
+
+	node.mark_inbound();
+	assert!(node.is_inbound_only());
+
+	// clones share the state, like routing table entries and lookup results
+	node.clone().mark_responded();
+	assert!(!node.is_inbound_only());
+}
+
+#[cfg(test)]
+use clock::MockClock;
+
+#[test]
+fn seen_by_clock() {
+	let clock = MockClock::new();
+	let node = Node::with_clock("127.0.0.1:2134", [0x00; NODEID_BYTELEN], Arc::new(clock.clone())).unwrap();
+
+	clock.advance(Duration::from_secs(120));
+	assert_eq!(node.unseen_for(clock.now()), Duration::from_secs(120));
+	assert!(format!("{:?}", node).contains("last_seen=2.00min ago"));
+}
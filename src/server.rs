@@ -1,14 +1,12 @@
-use std::time::Duration;
 use std::thread::{spawn,sleep};
 use std::sync::mpsc::{Sender,Receiver,channel};
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
 use std::str;
 use std::io;
-use std::net::{SocketAddr};
+use std::net::{SocketAddr,IpAddr,Ipv4Addr,Ipv6Addr};
 use std::collections::HashMap;
 
-use bincode::{serialize, deserialize, Bounded};
-
 use futures::prelude::*;
 use futures::Future;
 use tokio_core::reactor::Handle;
@@ -16,10 +14,14 @@ use tokio_core::reactor::Timeout;
 use tokio_core::net::UdpSocket;
 use tokio_core::net::UdpFramed;
 
+use clock::{Duration, SharedClock};
 use utils::ignore;
 use utils;
 use utils::semaphore::Semaphore;
-use message::{Message, Cookie};
+use message::{self, Message, Cookie};
+use codec::{self, SharedCodec};
+use cost::CostMeter;
+use error_log::{ErrorLog, ErrorCategory};
 use node::Node;
 
 pub struct Server {
@@ -27,13 +29,19 @@ pub struct Server {
 	pub local_addr: SocketAddr,
 	sink: SplitSink<UdpFramed<Codec>>,
 	stream:  SplitStream<UdpFramed<Codec>>,
+	codec:   SharedCodec,
+	errors:  ErrorLog,
+	clock:   SharedClock,
+	stopped: Arc<AtomicBool>,
 	pending_requests: Rc<RefCell<HashMap<(SocketAddr, Cookie), Sender<Message>>>>
 }
 
 // TODO: cleanup 'pending_requests' from time to time!
 
 impl Server {
-	pub fn new(handle: Handle, sock: UdpSocket) -> Result<Server> {
+	pub fn new(handle: Handle, sock: UdpSocket, codec: SharedCodec, errors: ErrorLog,
+		clock: SharedClock) -> Result<Server>
+	{
 		info!("Listening on {:?}", sock.local_addr());
 		let local_addr = sock.local_addr()?;
 		let sink, stream = sock.framed(Codec).split();
@@ -42,10 +50,52 @@ impl Server {
 			local_addr,
 			sink,
 			stream
+			codec,
+			errors,
+			clock,
+			stopped: Arc::new(AtomicBool::new(false)),
 			pending_requests: Rc::new(RefCell::new(HashMap::new())),
 		}
 	}
 
+	/// Make the iterator return None. It is woken up by an empty datagram
+	/// to ourselves, so this takes effect immediately.
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::SeqCst);
+
+		let mut addr = self.local_addr;
+		if addr.ip().is_unspecified() {
+			let loopback = match addr.ip() {
+				IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+				IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+			};
+			addr.set_ip(loopback);
+		}
+		ignore(self.sock.send_to(&[], addr));
+	}
+
+	/// Where to send datagrams for `addr`: IPv4 peers have to be addressed
+	/// by their mapped IPv6 address if we are bound to an IPv6 socket
+	fn destination(&self, addr: SocketAddr) -> SocketAddr {
+		match self.local_addr {
+			SocketAddr::V4(_) => addr,
+			SocketAddr::V6(_) => utils::ip6_mapped(addr),
+		}
+	}
+
+	pub fn is_stopped(&self) -> bool {
+		self.stopped.load(Ordering::SeqCst)
+	}
+
+	/// Size of `msg` on the wire
+	pub fn encoded_len(&self, msg: &Message) -> usize {
+		self.encode(msg).map(|buf| buf.len()).unwrap_or(0)
+	}
+
+	fn encode(&self, msg: &Message) -> io::Result<Vec<u8>> {
+		self.codec.encode(msg).map(codec::add_version)
+	}
+
 	/// just send a message and don't care about the reponse
 	pub fn hit_and_run(&self, addr: SocketAddr, req: &Message) {
 		self.send(addr, req);
@@ -63,22 +113,42 @@ impl Server {
 		debug!("Sending {:?} to {:?}", req, addr);
 		let (tx, rx) = channel();
 
+		let buf = match self.encode(&req) {
+			Ok(buf) => buf,
+			Err(e) => {
+				self.errors.log(ErrorCategory::Encode, addr, e);
+				// like a request nobody answered
+				ignore(tx.send(Message::Timeout));
+				return rx;
+			},
+		};
+
 		{
 			let mut pending = self.pending_requests.lock().unwrap();
 			let key = (addr, *req.cookie().unwrap());
 			(*pending).insert(key, tx);
 		}
 
-		let buf = serialize(&req, Bounded(2048)).unwrap();
-		self.sock.send_to(&buf[..], addr).unwrap();
+		if let Err(e) = self.sock.send_to(&buf[..], self.destination(addr)) {
+			// e.g. an IPv6 peer while we are bound to an IPv4 socket
+			debug!("Cannot send to {}: {}", addr, e);
+		}
 
 		rx
 	}
 
 	pub fn send_response(&self, addr: SocketAddr, resp: &Message)
 	{
-		let buf = serialize(&resp, Bounded(2048)).unwrap();
-		self.sock.send_to(&buf[..], addr).unwrap();
+		let buf = match self.encode(&resp) {
+			Ok(buf) => buf,
+			Err(e) => {
+				self.errors.log(ErrorCategory::Encode, addr, e);
+				return;
+			},
+		};
+		if let Err(e) = self.sock.send_to(&buf[..], self.destination(addr)) {
+			debug!("Cannot send to {}: {}", addr, e);
+		}
 	}
 
 	pub fn send_request_ms(&self, addr: &SocketAddr, req: &Message, timeout: u32)
@@ -86,15 +156,23 @@ impl Server {
 	{
 		let (tx, rx) = channel();
 
+		debug!("Sending {:?} to {:?}", req, addr);
+		let buf = match self.encode(&req) {
+			Ok(buf) => buf,
+			Err(e) => {
+				self.errors.log(ErrorCategory::Encode, *addr, e);
+				ignore(tx.send(Message::Timeout));
+				return rx;
+			},
+		};
+
 		{
 			let mut pending = self.pending_requests.lock().unwrap();
 			let key = (*addr, *req.cookie().unwrap());
 			(*pending).insert(key, tx.clone());
 		}
 
-		debug!("Sending {:?} to {:?}", req, addr);
-		let buf = serialize(&req, Bounded(2048)).unwrap();
-		ignore(self.sock.send_to(&buf[..], addr));
+		ignore(self.sock.send_to(&buf[..], self.destination(*addr)));
 
 		let handle = self.handle.clone();
 		handle.spawn_fn(move || {
@@ -111,6 +189,9 @@ impl Server {
 
 	/// returns an Channel you can use as an Iterator of type [(addr_index, Message), ...]
 	///
+	/// `timeout` is only used for nodes whose RTT we do not know yet,
+	/// see `Node::timeout_ms()`
+	///
 	/// just consume it until you got a reponse that satisfies your requirements
 	/// (You probably do not want to call iter.collect(): it will ask ALL nodes!)
 	pub fn send_many_request<I>(&self, iter: I, req: Message,
@@ -118,28 +199,78 @@ impl Server {
 		-> Receiver<(Node, Message)>
 			where I: 'static + Iterator<Item=Node> + Send
 	{
+		self.send_many_request_metered(iter, req, timeout, concurrency, None)
+	}
+
+	/// Like `send_many_request()`, but tells `meter` when queries start
+	/// and finish
+	pub fn send_many_request_metered<I>(&self, iter: I, req: Message,
+	                    timeout: u32, concurrency: isize, meter: Option<CostMeter>)
+		-> Receiver<(Node, Message)>
+			where I: 'static + Iterator<Item=Node> + Send
+	{
+		let sem = Arc::new(Semaphore::new(concurrency));
+		self.send_many_request_shared(iter, req, timeout, sem, meter)
+	}
+
+	/// Like `send_many_request_metered()`, but the concurrency is limited by
+	/// `sem`, which may be shared with other calls
+	pub fn send_many_request_shared<I>(&self, iter: I, req: Message,
+	                    timeout: u32, sem: Arc<Semaphore>, meter: Option<CostMeter>)
+		-> Receiver<(Node, Message)>
+			where I: 'static + Iterator<Item=Node> + Send
+	{
+		self.send_many_request_with(iter, move |_| req.clone(), timeout, sem, meter)
+	}
+
+	/// Like `send_many_request_shared()`, but each node gets the request
+	/// `make_req` builds for it (e.g. signed for that recipient)
+	pub fn send_many_request_with<I,F>(&self, iter: I, make_req: F,
+	                    timeout: u32, sem: Arc<Semaphore>, meter: Option<CostMeter>)
+		-> Receiver<(Node, Message)>
+			where I: 'static + Iterator<Item=Node> + Send,
+			      F: 'static + Fn(&Node) -> Message + Send + Sync
+	{
+		let make_req = Arc::new(make_req);
 		let is_rx_dead = Arc::new(Mutex::new(false));
 		let (tx, rx) = channel();
 
 		let this = self.clone();
 		self.handle.spawn_fn(move || {
-			let sem = Arc::new(Semaphore::new(concurrency));
-
 			for node in iter.take_while(|_| *(is_rx_dead.lock().unwrap()) == false) {
 				let is_rx_dead = is_rx_dead.clone();
-				let req = req.clone();
+				let req = make_req(&node);
 				let node = node.clone();
 				let this = this.clone();
 				let sem = sem.clone();
 				let tx = tx.clone();
+				let meter = meter.clone();
 
 				// acquire BEFORE we spawn!
 				sem.acquire();
 
 				this.handle.spawn_fn(move || {
+					if let Some(ref m) = meter {
+						m.query_started();
+					}
+					let sent = this.clock.now();
+					let timeout = node.timeout_ms(timeout);
 					let rx = this.send_request_ms(&node.addr, &req, timeout);
+					let mut measured = false;
 					
 					for resp in rx {
+						if !measured && resp != Message::Timeout {
+							let rtt = this.clock.now() - sent;
+							node.update_rtt(rtt);
+							debug!("RTT of {:?}: {:?} (smoothed {:?})", node, rtt, node.rtt());
+							node.reset_failures();
+							node.mark_responded();
+							measured = true;
+						}
+						if !measured && resp == Message::Timeout {
+							node.record_failure();
+						}
+
 						if tx.send((node.clone(), resp.clone())).is_err() {
 							*(is_rx_dead.lock().unwrap()) = true;
 						}
@@ -148,6 +279,9 @@ impl Server {
 							break;
 						}
 					}
+					if let Some(ref m) = meter {
+						m.query_finished();
+					}
 					sem.release();
 
 					Ok(())
@@ -162,20 +296,44 @@ impl Server {
 	}
 }
 
+/// Yields (sender, its protocol version, message)
 impl Iterator for Server {
-	type Item = (SocketAddr, Message);
+	type Item = (SocketAddr, u8, Message);
 
 	fn next(&mut self) -> Option<Self::Item> {
 		let mut buf = [0; 64*1024];
 
 		loop {
+			if self.is_stopped() {
+				return None;
+			}
+
 			let (len, src) = self.sock.recv_from(&mut buf).unwrap();
+			if self.is_stopped() {
+				return None;
+			}
 			let src = utils::ip4or6(src);
-			let msg = &buf[..len];
 
-			let msg:Result<Message,_> = deserialize(msg);
+			let (version, msg) = match codec::split_version(&buf[..len]) {
+				Some(split) => split,
+				None => {
+					self.errors.log(ErrorCategory::MalformedMessage, src, "empty datagram");
+					continue;
+				}
+			};
+			if !message::is_compatible(version) {
+				let err = format!("protocol version {} (we speak {} to {})", version,
+					message::MIN_PROTOCOL_VERSION, message::PROTOCOL_VERSION);
+				self.errors.log(ErrorCategory::IncompatibleVersion, src, err);
+				continue;
+			}
+
+			let msg = self.codec.decode(msg);
 
 			debug!("got {:?}", msg);
+			if let Err(ref e) = msg {
+				self.errors.log(ErrorCategory::MalformedMessage, src, e);
+			}
 
 			// dispatch responses
 			match msg {
@@ -183,13 +341,19 @@ impl Iterator for Server {
 				| Ok(Message::FindNode(_))
 				| Ok(Message::FindValue(_))
 				| Ok(Message::Store(_))
+				| Ok(Message::Delete(_))
 				| Ok(Message::Listen(_))
+				| Ok(Message::FindValues(_))
+				| Ok(Message::Hello(_))
 				| Ok(Message::Timeout)
 				| Err(_) => (),
 
 				Ok(ref resp @ Message::Pong(_))
 				| Ok(ref resp @ Message::FoundNode(_))
-				| Ok(ref resp @ Message::FoundValue(_)) => {
+				| Ok(ref resp @ Message::FoundValue(_))
+				| Ok(ref resp @ Message::FoundValues(_))
+				| Ok(ref resp @ Message::HelloAck(_))
+				| Ok(ref resp @ Message::StoreResponse(_)) => {
 					let key = (src, *resp.cookie().unwrap());
 					let pending = self.pending_requests.lock().unwrap();
 					
@@ -202,7 +366,7 @@ impl Iterator for Server {
 
 			match msg {
 				Err(_) | Ok(Message::Timeout) => (),
-				Ok(r) => return Some((src, r)),
+				Ok(r) => return Some((src, version, r)),
 			}
 		}
 	}
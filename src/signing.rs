@@ -0,0 +1,51 @@
+use crypto::ed25519;
+use rand::{OsRng, Rng};
+
+pub const PUBLIC_KEY_BYTELEN: usize = 32;
+
+pub type PublicKey = [u8; PUBLIC_KEY_BYTELEN];
+
+/// An ed25519 key pair
+#[derive(Clone)]
+pub struct KeyPair {
+	pub public: PublicKey,
+	secret:     Vec<u8>,
+}
+
+impl KeyPair {
+	#[allow(dead_code)]
+	pub fn generate() -> KeyPair {
+		let mut seed = [0u8; 32];
+		OsRng::new().unwrap().fill_bytes(&mut seed);
+		Self::from_seed(&seed)
+	}
+
+	pub fn from_seed(seed: &[u8]) -> KeyPair {
+		let (secret, public) = ed25519::keypair(seed);
+
+		KeyPair {
+			public: public,
+			secret: secret.to_vec(),
+		}
+	}
+
+	pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+		ed25519::signature(msg, &self.secret[..]).to_vec()
+	}
+}
+
+pub fn verify(msg: &[u8], public_key: &PublicKey, signature: &[u8]) -> bool {
+	signature.len() == 64 && ed25519::verify(msg, &public_key[..], signature)
+}
+
+#[test]
+fn sign_and_verify() {
+	let alice = KeyPair::from_seed(&[0x01; 32]);
+	let bob = KeyPair::from_seed(&[0x02; 32]);
+
+	let signature = alice.sign(b"hello");
+	assert!(verify(b"hello", &alice.public, &signature[..]));
+	assert!(!verify(b"hello", &bob.public, &signature[..]));
+	assert!(!verify(b"hallo", &alice.public, &signature[..]));
+	assert!(!verify(b"hello", &alice.public, &signature[..10]));
+}
@@ -1,62 +1,600 @@
 use std::sync::{Arc,Mutex};
 use std::collections::HashMap;
-use std::time::Duration;
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::net::{SocketAddr, IpAddr};
 
+use clock::{self, Duration, Instant, SharedClock};
 use node::NodeId;
+use signing::{self, PublicKey};
+use message::{RecordSignature, delete_msg};
+
+/// Deleted values we remember at most, see `delete()`
+const MAX_TOMBSTONES: usize = 4096;
+
+#[cfg(test)]
+use clock::MockClock;
+#[cfg(test)]
+use node::NODEID_BYTELEN;
+
+/// A value some other node stored on this node
+#[derive(Clone)]
+pub struct Entry {
+	pub value:     Vec<u8>,
+	pub sender:    (SocketAddr, NodeId),
+	pub stored_at: Instant,
+	/// if set, only these requesters may read the value (see `is_readable_by`)
+	pub acl:       Option<Vec<PublicKey>>,
+	/// how often other holders extended the entry (see `extend()`)
+	/// since its publisher stored it
+	pub holder_refreshes: usize,
+	/// the entry expires at `stored_at + ttl`
+	pub ttl:       Duration,
+	/// the publisher's signature, handed on to requesters
+	pub signature: Option<RecordSignature>,
+}
+
+impl Entry {
+	pub fn is_readable_by(&self, requester: Option<&PublicKey>) -> bool {
+		match self.acl {
+			None => true,
+			Some(ref acl) => requester.map(|r| acl.contains(r)).unwrap_or(false),
+		}
+	}
+}
+
+/// What the senders at one IP address store on us, see `exceeds_quota()`
+#[derive(Default)]
+struct Usage {
+	/// number of entries per key
+	keys:  HashMap<NodeId, usize>,
+	/// of their entries and tombstones
+	bytes: usize,
+}
+
+/// Takes back what was counted for the sender at `ip`: `bytes` and, for
+/// an entry, one entry of `key`
+fn release(usage: &mut HashMap<IpAddr, Usage>, ip: IpAddr, key: Option<&NodeId>, bytes: usize) {
+	let unused = match usage.get_mut(&ip) {
+		None => false,
+		Some(u) => {
+			if let Some(key) = key {
+				let last = match u.keys.get_mut(key) {
+					Some(n) => { *n -= 1; *n == 0 },
+					None => false,
+				};
+				if last {
+					u.keys.remove(key);
+				}
+			}
+			u.bytes -= bytes;
+			u.keys.is_empty() && u.bytes == 0
+		},
+	};
+	if unused {
+		usage.remove(&ip);
+	}
+}
+
+/// Signed entries may only be deleted by their signer
+fn may_delete(entry: &Entry, key: &NodeId, signature: Option<&RecordSignature>) -> bool {
+	match (entry.signature.as_ref(), signature) {
+		(None, _) => true,
+		(Some(_), None) => false,
+		(Some(stored), Some(sig)) => stored.public_key == sig.public_key &&
+			signing::verify(&delete_msg(key, &entry.value)[..], &sig.public_key, &sig.signature[..]),
+	}
+}
 
 #[allow(non_snake_case)]
 #[derive(Clone)]
 pub struct ExternalStorage {
-	storage: Arc<Mutex<HashMap<NodeId, Vec<(Vec<u8>, (SocketAddr, NodeId), Instant)>>>>,
+	storage: Arc<Mutex<HashMap<NodeId, Vec<Entry>>>>,
+	/// kept up to date with `storage` (and locked after it) by `add()` and `remove_where()`
+	usage:   Arc<Mutex<HashMap<IpAddr, Usage>>>,
+	/// (key, sender, value) of deleted values and until when they may
+	/// not be stored again, see `delete()`
+	tombstones: Arc<Mutex<HashMap<(NodeId, (SocketAddr, NodeId), Vec<u8>), Instant>>>,
 	ttl:     Duration,
+	clock:   SharedClock,
 }
 
 impl ExternalStorage {
 	pub fn new(ttl: Duration) -> ExternalStorage {
+		Self::with_clock(ttl, clock::system())
+	}
+
+	pub fn with_clock(ttl: Duration, clock: SharedClock) -> ExternalStorage {
 		ExternalStorage {
 			storage: Arc::new(Mutex::new(HashMap::new())),
+			usage: Arc::new(Mutex::new(HashMap::new())),
+			tombstones: Arc::new(Mutex::new(HashMap::new())),
 			ttl: ttl,
+			clock: clock,
 		}
 	}
 
 	pub fn put(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Vec<u8>) {
+		self.put_with_acl(key, sender, value, None)
+	}
+
+	pub fn put_with_acl(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Vec<u8>,
+		acl: Option<Vec<PublicKey>>)
+	{
+		self.put_with_ttl(key, sender, value, acl, None)
+	}
+
+	/// Like `put_with_acl()`, but the value expires after `ttl` instead of
+	/// the storage's TTL (if given)
+	pub fn put_with_ttl(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Vec<u8>,
+		acl: Option<Vec<PublicKey>>, ttl: Option<Duration>)
+	{
+		self.put_signed(key, sender, value, acl, ttl, None)
+	}
+
+	/// Like `put_with_ttl()`, for a value signed by its publisher
+	pub fn put_signed(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Vec<u8>,
+		acl: Option<Vec<PublicKey>>, ttl: Option<Duration>, signature: Option<RecordSignature>)
+	{
+		self.cleanup();
+
+		let mut storage = self.storage.lock().unwrap();
+		self.insert(&mut storage, key, sender, value, acl, ttl, signature);
+	}
+
+	/// Like `put_signed()`, but only if no one else stored a value for `key`.
+	/// Checked and stored under the same lock, so of several racing
+	/// senders only the first one gets it. Returns whether it was stored.
+	pub fn put_if_absent(&mut self, key: NodeId, sender: (SocketAddr, NodeId), value: Vec<u8>,
+		acl: Option<Vec<PublicKey>>, ttl: Option<Duration>, signature: Option<RecordSignature>) -> bool
+	{
 		self.cleanup();
 
 		let mut storage = self.storage.lock().unwrap();
-		
-		let mut s = storage.remove(&key).unwrap_or(vec![]);
-		s.iter()
-			.position(|&(ref v, ref s, _)| *v == value || *s == sender)
-			.map(|pos| s.remove(pos));
-		
-		let now = Instant::now();
-		s.push((value, sender, now));
+		let taken = storage.get(&key)
+			.map(|s| s.iter().any(|e| e.sender != sender))
+			.unwrap_or(false);
+		if !taken {
+			self.insert(&mut storage, key, sender, value, acl, ttl, signature);
+		}
+		!taken
+	}
 
-		storage.insert(key, s);
+	fn insert(&self, storage: &mut HashMap<NodeId, Vec<Entry>>, key: NodeId, sender: (SocketAddr, NodeId),
+		value: Vec<u8>, acl: Option<Vec<PublicKey>>, ttl: Option<Duration>, signature: Option<RecordSignature>)
+	{
+		let replaced = storage.get(&key)
+			.and_then(|s| s.iter().find(|e| e.value == value || e.sender == sender))
+			.map(|e| (e.sender, e.value.clone()));
+		if let Some((old_sender, old_value)) = replaced {
+			self.remove_where(storage, &key, |e| e.sender == old_sender && e.value == old_value);
+		}
+
+		self.add(storage, key, Entry {
+			value:     value,
+			sender:    sender,
+			stored_at: self.clock.now(),
+			acl:       acl,
+			holder_refreshes: 0,
+			ttl:       ttl.unwrap_or(self.ttl),
+			signature: signature,
+		});
+	}
+
+	fn add(&self, storage: &mut HashMap<NodeId, Vec<Entry>>, key: NodeId, entry: Entry) {
+		{
+			let mut usage = self.usage.lock().unwrap();
+			let u = usage.entry(entry.sender.0.ip()).or_insert_with(Usage::default);
+			*u.keys.entry(key).or_insert(0) += 1;
+			u.bytes += entry.value.len();
+		}
+		storage.entry(key).or_insert_with(Vec::new).push(entry);
+	}
+
+	/// Removes the entries of `key` matching `pred`, returns how many
+	fn remove_where<F>(&self, storage: &mut HashMap<NodeId, Vec<Entry>>, key: &NodeId, mut pred: F) -> usize
+		where F: FnMut(&Entry) -> bool
+	{
+		let (removed, kept): (Vec<Entry>, Vec<Entry>) = match storage.remove(key) {
+			None => return 0,
+			Some(entries) => entries.into_iter().partition(|e| pred(e)),
+		};
+		if !kept.is_empty() {
+			storage.insert(*key, kept);
+		}
+
+		let mut usage = self.usage.lock().unwrap();
+		for e in removed.iter() {
+			release(&mut usage, e.sender.0.ip(), Some(key), e.value.len());
+		}
+		removed.len()
 	}
 
 	pub fn get(&mut self, key: &NodeId) -> Vec<((SocketAddr, NodeId), Vec<u8>)> {
+		self.get_entries(key).into_iter()
+			.map(|e| (e.sender, e.value))
+			.collect()
+	}
+
+	pub fn get_entries(&mut self, key: &NodeId) -> Vec<Entry> {
 		self.cleanup();
 
 		let storage = self.storage.lock().unwrap();
+		storage.get(key).map(|vec| vec.clone()).unwrap_or(vec![])
+	}
+
+	pub fn remove_key(&mut self, key: &NodeId) {
+		let mut storage = self.storage.lock().unwrap();
+		self.remove_where(&mut storage, key, |_| true);
+	}
+
+	/// Removes the entries `sender` stored for `key` (but not those of others)
+	pub fn remove_from(&mut self, key: &NodeId, sender: &(SocketAddr, NodeId)) {
+		let mut storage = self.storage.lock().unwrap();
+		self.remove_where(&mut storage, key, |e| e.sender == *sender);
+	}
+
+	/// Like `get_entries()`, but only the `n` most recently stored values
+	/// `requester` may read, newest first
+	pub fn get_newest(&mut self, key: &NodeId, n: usize, requester: Option<&PublicKey>) -> Vec<Entry> {
+		let mut entries:Vec<Entry> = self.get_entries(key).into_iter()
+			.filter(|e| e.is_readable_by(requester))
+			.collect();
+		entries.reverse();
+		entries.truncate(n);
+		entries
+	}
+
+	/// Entries that will expire within `period`
+	pub fn expiring(&mut self, period: Duration) -> Vec<(NodeId, Entry)> {
+		self.cleanup();
+
+		let deadline = self.clock.now() + period;
+		let storage = self.storage.lock().unwrap();
+
+		storage.iter()
+			.flat_map(|(key, entries)| entries.iter().map(move |e| (*key, e.clone())))
+			.filter(|&(_, ref e)| e.stored_at + e.ttl <= deadline)
+			.collect()
+	}
+
+	/// Would storing `len` bytes for `key` from `sender` let its IP address
+	/// hold more than `max_keys` keys or `max_bytes` bytes?
+	/// The entry the store would replace does not count.
+	pub fn exceeds_quota(&mut self, key: &NodeId, sender: &(SocketAddr, NodeId), len: usize,
+		max_keys: usize, max_bytes: usize) -> bool
+	{
+		self.cleanup();
+
+		let storage = self.storage.lock().unwrap();
+		let usage = self.usage.lock().unwrap();
+		let (keys, bytes) = match usage.get(&sender.0.ip()) {
+			None => (1, 0),
+			Some(u) => (u.keys.len() + if u.keys.contains_key(key) { 0 } else { 1 }, u.bytes),
+		};
+		// with the entry the store would replace
+		let replaced = storage.get(key)
+			.and_then(|s| s.iter().find(|e| e.sender == *sender))
+			.map(|e| e.value.len())
+			.unwrap_or(0);
+
+		keys > max_keys || bytes - replaced + len > max_bytes
+	}
+
+	/// Time until the last value stored for `key` expires
+	pub fn remaining_ttl(&mut self, key: &NodeId) -> Option<Duration> {
+		let now = self.clock.now();
+
+		self.get_entries(key).iter()
+			.map(|e| (e.stored_at + e.ttl).duration_since(now))
+			.max()
+	}
+
+	/// Remove `value` if `sender` stored it (a signed value only if
+	/// `signature` signs its `delete_msg()` with the same key), and refuse
+	/// to store it from `sender` again for `ttl`. The tombstone counts
+	/// against the quota of `sender`'s IP address. Returns whether there
+	/// was such a value.
+	pub fn delete(&mut self, key: &NodeId, sender: &(SocketAddr, NodeId), value: &[u8],
+		signature: Option<&RecordSignature>, ttl: Duration) -> bool
+	{
+		self.cleanup();
+
+		let mut storage = self.storage.lock().unwrap();
+		let removed = self.remove_where(&mut storage, key, |e| {
+			e.sender == *sender && &e.value[..] == value && may_delete(e, key, signature)
+		});
+		if removed == 0 {
+			return false;
+		}
+
+		let mut tombstones = self.tombstones.lock().unwrap();
+		let tombstone = (*key, *sender, value.to_vec());
+		if tombstones.len() >= MAX_TOMBSTONES && !tombstones.contains_key(&tombstone) {
+			debug!("Too many tombstones, {} may store the deleted value again", sender.0);
+		} else if tombstones.insert(tombstone, self.clock.now() + ttl).is_none() {
+			let mut usage = self.usage.lock().unwrap();
+			usage.entry(sender.0.ip()).or_insert_with(Usage::default).bytes += value.len();
+		}
+		true
+	}
+
+	/// Was `value` deleted by `sender` (and may not be stored yet)?
+	pub fn is_deleted(&mut self, key: &NodeId, sender: &(SocketAddr, NodeId), value: &[u8]) -> bool {
+		self.expire_tombstones();
+
+		let tombstones = self.tombstones.lock().unwrap();
+		tombstones.contains_key(&(*key, *sender, value.to_vec()))
+	}
+
+	fn expire_tombstones(&self) {
+		let now = self.clock.now();
+		let mut tombstones = self.tombstones.lock().unwrap();
 
-		match storage.get(key) {
-			None => vec![],
-			Some(vec) => vec.clone().into_iter()
-				.map(|(v,n,_)| (n,v)).collect()
+		let expired:Vec<(NodeId, (SocketAddr, NodeId), Vec<u8>)> = tombstones.iter()
+			.filter(|&(_, &expires)| expires <= now)
+			.map(|(tombstone, _)| tombstone.clone())
+			.collect();
+		if expired.is_empty() {
+			return;
+		}
+
+		let mut usage = self.usage.lock().unwrap();
+		for tombstone in expired {
+			tombstones.remove(&tombstone);
+			let (_, sender, value) = tombstone;
+			release(&mut usage, sender.0.ip(), None, value.len());
+		}
+	}
+
+	/// Restart the TTL of `value` without its publisher storing it again
+	pub fn extend(&mut self, key: &NodeId, value: &[u8]) {
+		let now = self.clock.now();
+		let mut storage = self.storage.lock().unwrap();
+
+		if let Some(entries) = storage.get_mut(key) {
+			for e in entries.iter_mut().filter(|e| &e.value[..] == value) {
+				e.stored_at = now;
+				e.holder_refreshes += 1;
+			}
 		}
 	}
 
 	fn cleanup(&mut self) {
-		let now = Instant::now();
+		let now = self.clock.now();
 		let mut storage = self.storage.lock().unwrap();
 
-		for (_, values) in storage.iter_mut() {
-			*values = (*values).clone().into_iter()
-				.filter(|&(_, _, ref ttl)| (*ttl) + self.ttl > now)
-				.collect();
+		let expired:Vec<NodeId> = storage.iter()
+			.filter(|&(_, entries)| entries.iter().any(|e| e.stored_at + e.ttl <= now))
+			.map(|(key, _)| *key)
+			.collect();
+		for key in expired.iter() {
+			self.remove_where(&mut storage, key, |e| e.stored_at + e.ttl <= now);
 		}
+		self.expire_tombstones();
+	}
+}
+
+#[test]
+fn expiry() {
+	let clock = MockClock::new();
+	let ttl = Duration::from_secs(60);
+	let mut storage = ExternalStorage::with_clock(ttl, Arc::new(clock.clone()));
+
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+	storage.put(key, sender, vec![1,2,3]);
+	assert_eq!(storage.get(&key).len(), 1);
+
+	clock.advance(Duration::from_secs(59));
+	assert_eq!(storage.get(&key).len(), 1);
+
+	clock.advance(Duration::from_secs(2));
+	assert_eq!(storage.get(&key).len(), 0);
+}
+
+#[test]
+fn newest() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let key = [0xff; NODEID_BYTELEN];
+
+	for i in 0..100u8 {
+		let sender = ("127.0.0.1:2134".parse().unwrap(), [i; NODEID_BYTELEN]);
+		storage.put(key, sender, vec![i]);
 	}
+
+	let values:Vec<Vec<u8>> = storage.get_newest(&key, 3, None).into_iter().map(|e| e.value).collect();
+	assert_eq!(values, vec![vec![99], vec![98], vec![97]]);
+}
+
+#[test]
+fn remove_key() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+
+	storage.put(key, sender, vec![1,2,3]);
+	storage.remove_key(&key);
+	assert_eq!(storage.get(&key).len(), 0);
+}
+
+#[test]
+fn remove_from() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let alice = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let bob = ("127.0.0.1:2135".parse().unwrap(), [0x01; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+
+	storage.put(key, alice, vec![1,2,3]);
+	storage.put(key, bob, vec![4,5,6]);
+	storage.remove_from(&key, &alice);
+	assert_eq!(storage.get(&key), vec![(bob, vec![4,5,6])]);
+}
+
+#[test]
+fn put_if_absent() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let alice = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let bob = ("127.0.0.1:2135".parse().unwrap(), [0x01; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+
+	assert!(storage.put_if_absent(key, alice, vec![1,2,3], None, None, None));
+	// a retransmission is fine, a second sender is not
+	assert!(storage.put_if_absent(key, alice, vec![1,2,3], None, None, None));
+	assert!(!storage.put_if_absent(key, bob, vec![4,5,6], None, None, None));
+	assert_eq!(storage.get(&key), vec![(alice, vec![1,2,3])]);
+}
+
+#[test]
+fn acl() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+	let alice = [0x01; 32];
+	let bob = [0x02; 32];
+
+	storage.put_with_acl(key, sender, vec![1,2,3], Some(vec![alice]));
+
+	let entry = storage.get_entries(&key).pop().unwrap();
+	assert!(entry.is_readable_by(Some(&alice)));
+	assert!(!entry.is_readable_by(Some(&bob)));
+	assert!(!entry.is_readable_by(None));
+}
+
+#[test]
+fn extend() {
+	let clock = MockClock::new();
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(clock.clone()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+
+	storage.put(key, sender, vec![1,2,3]);
+	assert_eq!(storage.expiring(Duration::from_secs(10)).len(), 0);
+
+	clock.advance(Duration::from_secs(55));
+	assert_eq!(storage.expiring(Duration::from_secs(10)).len(), 1);
+
+	storage.extend(&key, &[1,2,3]);
+	assert_eq!(storage.expiring(Duration::from_secs(10)).len(), 0);
+	assert_eq!(storage.get_entries(&key)[0].holder_refreshes, 1);
+
+	clock.advance(Duration::from_secs(59));
+	assert_eq!(storage.get(&key).len(), 1);
+	clock.advance(Duration::from_secs(2));
+	assert_eq!(storage.get(&key).len(), 0);
+}
+
+#[test]
+fn per_value_ttl() {
+	let clock = MockClock::new();
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(clock.clone()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	let presence = [0x01; NODEID_BYTELEN];
+	let config = [0x02; NODEID_BYTELEN];
+
+	storage.put_with_ttl(presence, sender, vec![1], None, Some(Duration::from_secs(10)));
+	storage.put(config, sender, vec![2]);
+	assert_eq!(storage.remaining_ttl(&presence), Some(Duration::from_secs(10)));
+	assert_eq!(storage.remaining_ttl(&config), Some(Duration::from_secs(60)));
+
+	clock.advance(Duration::from_secs(11));
+	assert_eq!(storage.get(&presence).len(), 0);
+	assert_eq!(storage.remaining_ttl(&presence), None);
+	assert_eq!(storage.remaining_ttl(&config), Some(Duration::from_secs(49)));
+}
+
+#[test]
+fn quota() {
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+	// another port does not help
+	let other_port = ("127.0.0.1:2135".parse().unwrap(), [0x01; NODEID_BYTELEN]);
+
+	storage.put([0x01; NODEID_BYTELEN], sender, vec![0; 10]);
+	storage.put([0x02; NODEID_BYTELEN], sender, vec![0; 10]);
+
+	assert!(storage.exceeds_quota(&[0x03; NODEID_BYTELEN], &other_port, 1, 2, 100));
+	assert!(storage.exceeds_quota(&[0x02; NODEID_BYTELEN], &other_port, 81, 2, 100));
+	assert!(!storage.exceeds_quota(&[0x02; NODEID_BYTELEN], &other_port, 80, 2, 100));
+	// replacing its own value
+	assert!(!storage.exceeds_quota(&[0x02; NODEID_BYTELEN], &sender, 90, 2, 100));
+}
+
+#[test]
+fn quota_released() {
+	let clock = MockClock::new();
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(clock.clone()));
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x00; NODEID_BYTELEN]);
+
+	storage.put([0x01; NODEID_BYTELEN], sender, vec![0; 50]);
+	storage.put_with_ttl([0x02; NODEID_BYTELEN], sender, vec![0; 50], None, Some(Duration::from_secs(10)));
+	assert!(storage.exceeds_quota(&[0x03; NODEID_BYTELEN], &sender, 1, 3, 100));
+
+	// expired values do not count anymore
+	clock.advance(Duration::from_secs(11));
+	assert!(!storage.exceeds_quota(&[0x03; NODEID_BYTELEN], &sender, 50, 2, 100));
+
+	// neither do removed ones
+	storage.remove_from(&[0x01; NODEID_BYTELEN], &sender);
+	assert!(!storage.exceeds_quota(&[0x03; NODEID_BYTELEN], &sender, 100, 1, 100));
+}
+
+#[test]
+fn tombstone() {
+	let clock = MockClock::new();
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(clock.clone()));
+	let alice = ("127.0.0.1:2134".parse().unwrap(), [0x01; NODEID_BYTELEN]);
+	let bob = ("127.0.0.1:2135".parse().unwrap(), [0x02; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+
+	storage.put(key, alice, vec![1,2,3]);
+
+	// bob cannot delete alice's value, not even with her NodeId
+	assert!(!storage.delete(&key, &bob, &[1,2,3], None, Duration::from_secs(30)));
+	assert!(!storage.delete(&key, &(bob.0, alice.1), &[1,2,3], None, Duration::from_secs(30)));
+	assert_eq!(storage.get(&key).len(), 1);
+	// and leaves no tombstone trying
+	assert!(!storage.is_deleted(&key, &bob, &[1,2,3]));
+
+	assert!(storage.delete(&key, &alice, &[1,2,3], None, Duration::from_secs(30)));
+	assert_eq!(storage.get(&key).len(), 0);
+	assert!(storage.is_deleted(&key, &alice, &[1,2,3]));
+	assert!(!storage.is_deleted(&key, &alice, &[4,5,6]));
+	// the tombstone counts against the quota
+	assert!(storage.exceeds_quota(&key, &alice, 98, 1, 100));
+
+	clock.advance(Duration::from_secs(31));
+	assert!(!storage.is_deleted(&key, &alice, &[1,2,3]));
+	assert!(!storage.exceeds_quota(&key, &alice, 98, 1, 100));
+}
+
+#[test]
+fn signed_delete() {
+	use signing::KeyPair;
+
+	let mut storage = ExternalStorage::with_clock(Duration::from_secs(60), Arc::new(MockClock::new()));
+	let alice = KeyPair::from_seed(&[0x01; 32]);
+	let mallory = KeyPair::from_seed(&[0x02; 32]);
+	let sender = ("127.0.0.1:2134".parse().unwrap(), [0x01; NODEID_BYTELEN]);
+	let key = [0xff; NODEID_BYTELEN];
+	let value = vec![1,2,3];
+
+	let record = RecordSignature {
+		public_key: alice.public,
+		signature:  alice.sign(&::message::record_msg(&key, &value)[..]),
+	};
+	storage.put_signed(key, sender, value.clone(), None, None, Some(record.clone()));
+
+	let by = |keypair: &KeyPair| RecordSignature {
+		public_key: keypair.public,
+		signature:  keypair.sign(&delete_msg(&key, &value)[..]),
+	};
+	let ttl = Duration::from_secs(30);
+
+	assert!(!storage.delete(&key, &sender, &value, None, ttl));
+	assert!(!storage.delete(&key, &sender, &value, Some(&by(&mallory)), ttl));
+	// the signature of the record is no signature of its deletion
+	assert!(!storage.delete(&key, &sender, &value, Some(&record), ttl));
+	assert_eq!(storage.get(&key).len(), 1);
+
+	assert!(storage.delete(&key, &sender, &value, Some(&by(&alice)), ttl));
+	assert_eq!(storage.get(&key).len(), 0);
 }
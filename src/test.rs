@@ -2,12 +2,69 @@
 
 use env_logger;
 
-use node::NODEID_BYTELEN;
-use kademlia::Kademlia;
+use node::{Node, NodeId, Distance, NODEID_BYTELEN};
+use kademlia::{Kademlia, BootstrapError, PutMode, GetError, Quorum, RoutingEventKind};
+use kademlia::{RecordVerifier, LargeGetError, verify_signature, PutError};
+use chunks::{Manifest, chunk_key, chunk_hash};
+use codec::{Codec, NativeCodec, add_version, split_version};
+use message::{Message, Ping, FindValues};
+use signing::KeyPair;
+use key::Key;
+use message::{PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION, FEATURE_BATCHING, FEATURE_COMPRESSION, RecordSignature};
+use config::{KademliaConfig, WireFormat, FarStorePolicy, AddressPolicy};
+
+use tokio_core::reactor::{Core, Handle};
 
 use std::thread::{spawn,sleep};
+use std::sync::Arc;
+use std::io;
+use std::io::Write;
+use std::fs::File;
+use std::env;
+use std::net::{UdpSocket, SocketAddr};
 use std::time::Duration;
 
+/// Tunables of all test nodes
+fn node_config() -> KademliaConfig {
+	KademliaConfig {
+		// all test nodes are on localhost
+		address_policy: AddressPolicy::AllowLocal,
+		.. KademliaConfig::default()
+	}
+}
+
+/// A node on an unused loopback port
+fn create_node(handle: &Handle, own_id: Option<NodeId>, config: KademliaConfig) -> Kademlia {
+	Kademlia::create_with_config(handle.clone(), ("127.0.0.1", 0), own_id, config).unwrap()
+}
+
+/// Let `kad` know `supernode`
+fn add_supernode(kad: &mut Kademlia, supernode: &Kademlia) {
+	let addr = supernode.local_addr().unwrap();
+	kad.add_supernode(&addr.to_string()).unwrap();
+}
+
+/// A supernode with NodeId 0x00.. and a node with NodeId 0xff.. that
+/// knows it
+fn pair(handle: &Handle) -> (Kademlia, Kademlia) {
+	pair_with_config(handle, node_config())
+}
+
+fn pair_with_config(handle: &Handle, config: KademliaConfig) -> (Kademlia, Kademlia) {
+	let kad_super = create_node(handle, Some([0x00; NODEID_BYTELEN]), config.clone());
+	let mut kad1 = create_node(handle, Some([0xFF; NODEID_BYTELEN]), config);
+	add_supernode(&mut kad1, &kad_super);
+
+	(kad_super, kad1)
+}
+
+/// An address nobody answers at (as long as the socket lives)
+fn silent_addr() -> (UdpSocket, SocketAddr) {
+	let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+	let addr = sock.local_addr().unwrap();
+	(sock, addr)
+}
+
 #[test]
 fn test() {
 	let _ = env_logger::init();
@@ -18,17 +75,19 @@ fn test() {
 	let ones = [0xFF; NODEID_BYTELEN];
 
 	let super_addr = ("127.0.0.1", 30000);
-	let kad_super = Kademlia::new_supernode(handle, super_addr, Some(zeros.clone()));
+	let kad_super = Kademlia::create_with_config(handle.clone(), super_addr, Some(zeros.clone()), node_config()).unwrap();
 
-	let mut kad1 = Kademlia::bootstrap("0.0.0.0:30001", vec![super_addr], Some(ones.clone()));
-	let mut kad2 = Kademlia::bootstrap("0.0.0.0:30002", vec![super_addr], Some(ones.clone()));
+	let mut kad1 = Kademlia::bootstrap_with_config(handle.clone(), "0.0.0.0:30001", vec![super_addr],
+		Some(ones.clone()), node_config()).unwrap();
+	let mut kad2 = Kademlia::bootstrap_with_config(handle, "0.0.0.0:30002", vec![super_addr],
+		Some(ones.clone()), node_config()).unwrap();
 
-	kad1.put(zeros.clone(), vec![1,2,3]).unwrap();
-	kad2.put(zeros.clone(), vec![4,5,6]).unwrap();
-	kad1.put(zeros.clone(), vec![7,8,9]).unwrap();
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	kad2.put(Key::new(zeros), vec![4,5,6]).unwrap();
+	kad1.put(Key::new(zeros), vec![7,8,9]).unwrap();
 
-	let result = kad1.get(zeros.clone());
-	let mut result = kad1.get(zeros);
+	let result = kad1.get(Key::new(zeros));
+	let mut result = kad1.get(Key::new(zeros));
 	result.sort_by(|a,b| a.cmp(b));
 	result.dedup();
 	assert_eq!(result, vec![vec![4,5,6], vec![7,8,9]]);
@@ -38,27 +97,917 @@ fn test() {
 #[test]
 fn test_concurrent() {
 	let _ = env_logger::init();
+	let core = Core::new().unwrap();
+	let handle = core.handle();
 
 	let zeros = [0x00; NODEID_BYTELEN];
 	let zeros1 = zeros.clone();
 	let ones = [0xFF; NODEID_BYTELEN];
 
 	let super_addr = ("127.0.0.1", 40000);
-	let kad_super = Kademlia::new_supernode(super_addr, Some(zeros.clone()));
+	let kad_super = Kademlia::create_with_config(handle.clone(), super_addr, Some(zeros.clone()), node_config()).unwrap();
 
-	let mut kad1 = Kademlia::bootstrap("0.0.0.0:40001", vec![super_addr], Some(ones.clone()));
-	let kad2 = Kademlia::bootstrap("0.0.0.0:40002", vec![super_addr], Some(ones.clone()));
+	let mut kad1 = Kademlia::bootstrap_with_config(handle.clone(), "0.0.0.0:40001", vec![super_addr],
+		Some(ones.clone()), node_config()).unwrap();
+	let kad2 = Kademlia::bootstrap_with_config(handle, "0.0.0.0:40002", vec![super_addr],
+		Some(ones.clone()), node_config()).unwrap();
 
 	let mut kad11 = kad1.clone();
 	spawn(move || {
-		kad11.put(zeros1.clone(), vec![1,2,3]).unwrap();
+		kad11.put(Key::new(zeros1), vec![1,2,3]).unwrap();
 	});
-	kad1.put(ones.clone(), vec![4,5,6]).unwrap();
+	kad1.put(Key::new(ones), vec![4,5,6]).unwrap();
 
 	sleep(Duration::from_millis(500));
-	let result = kad1.get(zeros.clone());
+	let result = kad1.get(Key::new(zeros));
 	assert_eq!(result, vec![vec![1,2,3]]);
 	
-	let result = kad1.get(ones.clone());
+	let result = kad1.get(Key::new(ones));
 	assert_eq!(result, vec![vec![4,5,6]]);
 }
+
+#[test]
+fn test_config() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let config = KademliaConfig {
+		k:     8,
+		alpha: 5,
+		.. node_config()
+	};
+	let kad = create_node(&handle, None, config);
+
+	let config = kad.config();
+	assert_eq!(config.k, 8);
+	assert_eq!(config.alpha, 5);
+	assert_eq!(config.ttl, KademliaConfig::default().ttl);
+	assert_eq!(config.timeout_ms, KademliaConfig::default().timeout_ms);
+}
+
+#[test]
+fn test_bencode() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let config = KademliaConfig {
+		wire_format: WireFormat::Bencode,
+		.. node_config()
+	};
+	let (kad_super, mut kad1) = pair_with_config(&handle, config);
+
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![1,2,3]]);
+}
+
+#[test]
+fn test_byte_budget() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+
+	let (_, unlimited) = kad1.get_with_cost(Key::new(zeros), None);
+	assert!(unlimited.queries > 0);
+	assert!(unlimited.avg_in_flight > 0.0);
+
+	let (_, limited) = kad1.get_with_cost(Key::new(zeros), Some(1));
+	assert_eq!(limited.queries, 1);
+	assert!(limited.total_bytes() > 1);
+	assert!(limited.total_bytes() <= unlimited.total_bytes());
+}
+
+#[test]
+fn test_put_if_absent() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+	let mut kad2 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad2, &kad_super);
+
+	let mut kad11 = kad1.clone();
+	let claim1 = spawn(move || kad11.put_if_absent(Key::new(zeros), vec![1]).unwrap());
+	let claim2 = kad2.put_if_absent(Key::new(zeros), vec![2]).unwrap();
+	let claim1 = claim1.join().unwrap();
+
+	// exactly one of them claims the key
+	assert!(claim1 != claim2);
+	sleep(Duration::from_millis(500));
+	assert_eq!(kad1.put_if_absent(Key::new(zeros), vec![3]), Ok(false));
+	assert_eq!(kad2.put_if_absent(Key::new(zeros), vec![4]), Ok(false));
+}
+
+#[test]
+fn test_pinned_id() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, Some(zeros.clone()), node_config());
+	let super_addr = kad_super.local_addr().unwrap();
+
+	let kad1 = Kademlia::bootstrap_pinned_with_config(handle.clone(), "0.0.0.0:0", vec![super_addr],
+		ones.clone(), node_config());
+	assert_eq!(kad1.unwrap().get_own_id(), ones);
+
+	let kad2 = Kademlia::bootstrap_pinned_with_config(handle, "0.0.0.0:0", vec![super_addr],
+		ones.clone(), node_config());
+	match kad2 {
+		Err(BootstrapError::IdTaken(id)) => assert_eq!(id, ones),
+		_ => panic!("NodeId should be taken"),
+	}
+}
+
+#[test]
+fn test_small_k() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let config = KademliaConfig {
+		k: 8,
+		.. node_config()
+	};
+	let kad_super = create_node(&handle, None, config.clone());
+	let super_addr = kad_super.local_addr().unwrap();
+
+	let kads:Vec<Kademlia> = (0..12)
+		.map(|_| Kademlia::bootstrap_with_config(handle.clone(), ("127.0.0.1", 0),
+			vec![super_addr], None, config.clone()).unwrap())
+		.collect();
+
+	let nodes = kad_super.find_node_with_distances(Node::generate_id());
+	assert!(nodes.len() <= 8);
+	assert!(kad_super.get_nodes().len() >= 8);
+}
+
+#[test]
+fn test_store_forwarding() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+	let mut near = zeros.clone();
+	near[NODEID_BYTELEN-1] = 0x01;
+
+	let config = KademliaConfig {
+		k: 1,
+		far_store_policy: FarStorePolicy::Forward,
+		.. node_config()
+	};
+	let mut kad_far = create_node(&handle, Some(ones.clone()), config);
+	let kad_near = create_node(&handle, Some(near.clone()), node_config());
+	add_supernode(&mut kad_far, &kad_near);
+
+	// a publisher that only knows kad_far
+	let mut publisher = create_node(&handle, None, node_config());
+	add_supernode(&mut publisher, &kad_far);
+	publisher.put(Key::new(zeros), vec![1,2,3]).unwrap();
+
+	sleep(Duration::from_millis(500));
+	assert_eq!(kad_far.cached_value_publishers(&Key::new(zeros)).len(), 0);
+	assert_eq!(kad_near.cached_value_publishers(&Key::new(zeros)).len(), 1);
+}
+
+#[test]
+fn test_put_replace() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, Some(zeros.clone()), node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	let mut kad2 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+	add_supernode(&mut kad2, &kad_super);
+
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	kad2.put_with_mode(Key::new(zeros), vec![4,5,6], PutMode::Append).unwrap();
+	sleep(Duration::from_millis(500));
+	assert_eq!(kad1.get(Key::new(zeros)).len(), 2);
+
+	// holders keep one value per publisher, even when appending
+	kad2.put_with_mode(Key::new(zeros), vec![7,8,9], PutMode::Append).unwrap();
+	sleep(Duration::from_millis(500));
+	let mut result = kad1.get(Key::new(zeros));
+	result.sort();
+	assert_eq!(result, vec![vec![1,2,3], vec![7,8,9]]);
+
+	// only replaces what kad2 stored, the value of kad1 stays
+	kad2.put_with_mode(Key::new(zeros), vec![10,11,12], PutMode::Replace).unwrap();
+	sleep(Duration::from_millis(500));
+	let mut result = kad1.get(Key::new(zeros));
+	result.sort();
+	assert_eq!(result, vec![vec![1,2,3], vec![10,11,12]]);
+}
+
+#[test]
+fn test_with_socket() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, Some(zeros.clone()), node_config());
+
+	let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+	sock.set_ttl(7).unwrap();
+	let addr = sock.local_addr().unwrap();
+
+	let mut kad1 = Kademlia::with_socket(handle, sock, None, node_config()).unwrap();
+	assert_eq!(kad1.local_addr().unwrap(), addr);
+
+	// the supernode learns about us from our requests
+	add_supernode(&mut kad1, &kad_super);
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	assert!(kad_super.get_nodes().iter().any(|n| n.addr == addr));
+}
+
+#[test]
+fn test_try_get() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, Some(zeros.clone()), node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+
+	match kad1.try_get(Key::new(ones)) {
+		Err(GetError::NotFound { queried }) => assert!(queried > 0),
+		res => panic!("{:?}", res),
+	}
+
+	let (_sock, silent) = silent_addr();
+	let mut kad2 = create_node(&handle, None, node_config());
+	kad2.add_supernode(&silent.to_string()).unwrap();
+	assert_eq!(kad2.try_get(Key::new(ones)), Err(GetError::Inconclusive { reached: 0 }));
+}
+
+#[test]
+fn test_max_replication_distance() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+	let mut near = zeros.clone();
+	near[NODEID_BYTELEN-1] = 0x01;
+
+	let kad_far = create_node(&handle, Some(ones.clone()), node_config());
+	let kad_near = create_node(&handle, Some(near.clone()), node_config());
+
+	let mut bound = [0x00; NODEID_BYTELEN];
+	bound[NODEID_BYTELEN-1] = 0xff;
+	let config = KademliaConfig {
+		max_replication_distance: Some(Distance(bound)),
+		.. node_config()
+	};
+	let mut kad1 = create_node(&handle, None, config);
+	add_supernode(&mut kad1, &kad_far);
+	add_supernode(&mut kad1, &kad_near);
+
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	sleep(Duration::from_millis(500));
+	assert_eq!(kad_far.cached_value_publishers(&Key::new(zeros)).len(), 0);
+	assert_eq!(kad_near.cached_value_publishers(&Key::new(zeros)).len(), 1);
+}
+
+#[test]
+fn test_query_capabilities() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad1 = create_node(&handle, None, node_config());
+	let kad2 = create_node(&handle, None, node_config());
+
+	let caps = kad2.query_capabilities(kad1.local_addr().unwrap()).unwrap();
+	assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+	assert_eq!(caps.protocol_version, PROTOCOL_VERSION);
+	assert!(caps.supports(FEATURE_BATCHING));
+	assert!(!caps.supports(FEATURE_COMPRESSION));
+
+	let (_sock, silent) = silent_addr();
+	assert!(kad2.query_capabilities(silent).is_err());
+}
+
+#[test]
+fn test_bind_conflict() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad1 = create_node(&handle, None, node_config());
+	let addr = kad1.local_addr().unwrap();
+
+	let kad2 = Kademlia::create_with_config(handle.clone(), addr, None, node_config());
+	assert_eq!(kad2.err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+
+	let (_sock, silent) = silent_addr();
+	let kad3 = Kademlia::bootstrap_with_config(handle, addr, vec![silent], None, node_config());
+	assert!(kad3.is_err());
+}
+
+#[test]
+fn test_bootstrap_attempts() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let config = KademliaConfig {
+		timeout_ms: 200,
+		max_bootstrap_attempts: 2,
+		.. node_config()
+	};
+	let (_sock, silent) = silent_addr();
+	let kad = Kademlia::bootstrap_with_config(handle, ("127.0.0.1", 0), vec![silent], None, config);
+
+	match kad {
+		Err(BootstrapError::NoSupernodeAnswered { attempts }) => assert_eq!(attempts, 2),
+		_ => panic!("bootstrap should fail"),
+	}
+}
+
+#[test]
+fn test_routing_table_snapshot() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let mut path = env::temp_dir();
+	path.push("bulletinboard_test_snapshot");
+
+	let kad_super = create_node(&handle, None, node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+	kad1.save_routing_table(&path).unwrap();
+
+	let kad2 = Kademlia::create_from_snapshot_with_config(handle, ("127.0.0.1", 0), None, &path,
+		node_config()).unwrap();
+	let addrs:Vec<_> = kad2.get_nodes().iter().map(|n| n.addr).collect();
+	assert_eq!(addrs, vec![kad_super.local_addr().unwrap()]);
+}
+
+#[test]
+fn test_forged_snapshot() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let mut path = env::temp_dir();
+	path.push("bulletinboard_test_forged_snapshot");
+
+	// claims to hold 2^64-1 contacts
+	let mut file = File::create(&path).unwrap();
+	file.write_all(&[0xff; 8]).unwrap();
+
+	let kad = Kademlia::create_from_snapshot_with_config(handle, ("127.0.0.1", 0), None, &path,
+		node_config());
+	assert_eq!(kad.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn test_shutdown() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad1 = create_node(&handle, None, node_config());
+	let kad2 = create_node(&handle, None, node_config());
+	let addr = kad1.local_addr().unwrap();
+	assert!(kad2.query_capabilities(addr).is_ok());
+
+	kad1.shutdown();
+	assert!(kad2.query_capabilities(addr).is_err());
+}
+
+#[test]
+fn test_shutdown_rebind() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad = create_node(&handle, None, node_config());
+	let addr = kad.local_addr().unwrap();
+	kad.shutdown();
+
+	// neither the receive loop nor the periodic tasks keep the socket open
+	assert!(Kademlia::create_with_config(handle.clone(), addr, None, node_config()).is_ok());
+}
+
+#[test]
+fn test_put_with_ttl() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	kad1.put_with_ttl(Key::new(zeros), vec![1,2,3], Duration::from_secs(1)).unwrap();
+	assert!(kad1.remaining_ttl(&Key::new(zeros)).unwrap() <= Duration::from_secs(1));
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![1,2,3]]);
+
+	sleep(Duration::from_millis(1100));
+	assert_eq!(kad1.remaining_ttl(&Key::new(zeros)), None);
+	assert_eq!(kad1.get(Key::new(zeros)), Vec::<Vec<u8>>::new());
+}
+
+#[test]
+fn test_put_with_subsecond_ttl() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	assert_eq!(kad1.put_with_ttl(Key::new(zeros), vec![1], Duration::from_secs(0)), Err(PutError::ZeroTtl));
+
+	// not truncated to zero seconds on the way
+	assert_eq!(kad1.put_with_ttl(Key::new(zeros), vec![1,2,3], Duration::from_millis(500)), Ok(1));
+	assert_eq!(kad_super.cached_value_publishers(&Key::new(zeros)).len(), 1);
+
+	sleep(Duration::from_millis(600));
+	assert_eq!(kad_super.cached_value_publishers(&Key::new(zeros)).len(), 0);
+}
+
+#[test]
+fn test_store_acks() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let config = KademliaConfig {
+		max_value_len: 4,
+		.. node_config()
+	};
+	let kad_super = create_node(&handle, None, config);
+	let mut kad1 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(1));
+	// too large for the supernode
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3,4,5]), Ok(0));
+}
+
+#[test]
+fn test_get_dedup() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, None, node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	let mut kad2 = create_node(&handle, None, node_config());
+	let mut kad3 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+	add_supernode(&mut kad2, &kad_super);
+	add_supernode(&mut kad3, &kad_super);
+	add_supernode(&mut kad3, &kad2);
+
+	// two publishers of the same value, so it arrives from several holders
+	kad1.put(Key::new(zeros), vec![1]).unwrap();
+	kad2.put(Key::new(zeros), vec![1]).unwrap();
+
+	assert_eq!(kad3.get(Key::new(zeros)), vec![vec![1]]);
+}
+
+#[test]
+fn test_dual_stack() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad6 = Kademlia::create_with_config(handle.clone(), "[::]:0", None, node_config()).unwrap();
+	let kad4 = create_node(&handle, None, node_config());
+	let port6 = kad6.local_addr().unwrap().port();
+
+	// v4 peers reach a [::] socket and are answered via mapped addresses
+	assert!(kad4.query_capabilities(("127.0.0.1", port6)).is_ok());
+	assert!(kad6.query_capabilities(kad4.local_addr().unwrap()).is_ok());
+	assert!(kad6.query_capabilities(("::1", port6)).is_ok());
+}
+
+#[test]
+fn test_store_quota() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let config = KademliaConfig {
+		max_keys_per_source: 2,
+		.. node_config()
+	};
+	let kad_super = create_node(&handle, None, config);
+	let mut kad1 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+
+	assert_eq!(kad1.put(Key::new([0x01; NODEID_BYTELEN]), vec![1]), Ok(1));
+	assert_eq!(kad1.put(Key::new([0x02; NODEID_BYTELEN]), vec![2]), Ok(1));
+	assert_eq!(kad1.put(Key::new([0x03; NODEID_BYTELEN]), vec![3]), Ok(0));
+	// replacing a value does not need more quota
+	assert_eq!(kad1.put(Key::new([0x02; NODEID_BYTELEN]), vec![2]), Ok(1));
+}
+
+#[test]
+fn test_republish_dedup() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, None, node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+
+	let threads = kad1.thread_count();
+	for _ in 0..5 {
+		kad1.put_with_ttl(Key::new(zeros), vec![1,2,3], Duration::from_secs(60)).unwrap();
+		kad1.remove_key(&Key::new(zeros));
+		kad1.put_with_ttl(Key::new(zeros), vec![1,2,3], Duration::from_secs(60)).unwrap();
+	}
+	assert_eq!(kad1.republished_count(), 1);
+	assert_eq!(kad1.thread_count(), threads);
+
+	kad1.remove(&Key::new(zeros), &[1,2,3]);
+	assert_eq!(kad1.republished_count(), 0);
+	assert_eq!(kad1.remaining_ttl(&Key::new(zeros)), None);
+}
+
+#[test]
+fn test_get_quorum() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let kad_super = create_node(&handle, None, node_config());
+	let mut kad1 = create_node(&handle, None, node_config());
+	let mut kad2 = create_node(&handle, None, node_config());
+	let mut kad3 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad1, &kad_super);
+	add_supernode(&mut kad1, &kad2);
+	add_supernode(&mut kad2, &kad_super);
+	add_supernode(&mut kad3, &kad_super);
+	add_supernode(&mut kad3, &kad2);
+
+	// stored on the supernode and kad2
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(2));
+
+	assert_eq!(kad3.get_quorum(Key::new(zeros), 2), Quorum::Reached(vec![1,2,3]));
+	assert_eq!(kad3.get_quorum(Key::new(zeros), 3), Quorum::Disputed(vec![(vec![1,2,3], 2)]));
+}
+
+#[test]
+fn test_routing_events() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad_super = create_node(&handle, None, node_config());
+	let events = kad_super.routing_events();
+
+	let kad1 = create_node(&handle, None, node_config());
+	kad1.query_capabilities(kad_super.local_addr().unwrap()).unwrap();
+
+	let event = events.recv_timeout(Duration::from_secs(1)).unwrap();
+	assert_eq!(event.kind, RoutingEventKind::Added);
+	assert_eq!(event.node.node_id, kad1.get_own_id());
+}
+
+#[test]
+fn test_lookup_limits() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let config = KademliaConfig {
+		max_lookup_queries: 1,
+		.. node_config()
+	};
+	let kad_super = create_node(&handle, Some(zeros.clone()), node_config());
+	let kad2 = create_node(&handle, None, node_config());
+	let mut kad1 = create_node(&handle, Some(ones.clone()), config);
+	add_supernode(&mut kad1, &kad_super);
+	add_supernode(&mut kad1, &kad2);
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+
+	let (values, cost) = kad1.get_with_cost(Key::new(zeros), None);
+	assert_eq!(cost.queries, 1);
+	assert_eq!(values, vec![vec![1,2,3]]);
+}
+
+#[test]
+fn test_signed_values() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let alice = KeyPair::from_seed(&[0x01; 32]);
+	let mallory = KeyPair::from_seed(&[0x02; 32]);
+	let alice_public = alice.public;
+
+	let config = KademliaConfig {
+		require_signatures: true,
+		.. node_config()
+	};
+	let verifier: RecordVerifier = Arc::new(move |key: &Key, value: &[u8], sig: &RecordSignature| {
+		verify_signature(key, value, sig) && sig.public_key == alice_public
+	});
+	let kad_super = Kademlia::create_with_verifier(handle.clone(), ("127.0.0.1", 0),
+		Some(zeros.clone()), config, verifier).unwrap();
+	let mut kad1 = create_node(&handle, Some(ones.clone()), node_config());
+	add_supernode(&mut kad1, &kad_super);
+
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(0));
+	assert_eq!(kad1.put_signed(Key::new(zeros), vec![4,5,6], &mallory), Ok(0));
+	assert_eq!(kad1.put_signed(Key::new(zeros), vec![7,8,9], &alice), Ok(1));
+
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![7,8,9]]);
+}
+
+#[test]
+fn test_get_large() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let ones = [0xFF; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	let value:Vec<u8> = (0..5000).map(|i| i as u8).collect();
+	kad1.put_large(Key::new(zeros), value.clone()).unwrap();
+	assert_eq!(kad1.get_large(Key::new(zeros)), Ok(value.clone()));
+
+	// someone else's value under the same chunk key is skipped
+	let mut kad2 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad2, &kad_super);
+	kad2.put(chunk_key(&Key::new(zeros), 0), vec![0xaa; 100]).unwrap();
+	assert_eq!(kad1.get_large(Key::new(zeros)), Ok(value));
+
+	assert_eq!(kad1.get_large(Key::new(ones)), Err(LargeGetError::NotFound));
+
+	// a manifest whose second chunk was never stored
+	let key = Key::new([0x01; NODEID_BYTELEN]);
+	kad1.put(chunk_key(&key, 0), vec![1,2,3]).unwrap();
+	let manifest = Manifest {
+		total_len: 6,
+		chunks:    vec![(chunk_key(&key, 0), chunk_hash(&[1,2,3])), (chunk_key(&key, 1), chunk_hash(&[4,5,6]))],
+	};
+	kad1.put(key, manifest.encode()).unwrap();
+	assert_eq!(kad1.get_large(key), Err(LargeGetError::MissingChunk {
+		index: 1,
+		key:   chunk_key(&key, 1),
+	}));
+}
+
+#[test]
+fn test_protocol_version() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let kad_super = create_node(&handle, None, node_config());
+	let kad1 = create_node(&handle, None, node_config());
+	let super_addr = kad_super.local_addr().unwrap();
+	kad1.query_capabilities(super_addr).unwrap();
+
+	let contact = kad_super.get_nodes().into_iter()
+		.find(|n| n.node_id == kad1.get_own_id())
+		.unwrap();
+	assert_eq!(contact.protocol_version(), Some(PROTOCOL_VERSION));
+
+	// a Ping of a version 1 node (bincode tag, then the raw ids) is dropped
+	let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+	sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+	let mut legacy_ping = vec![0,0,0,0];
+	legacy_ping.extend(vec![0x01; NODEID_BYTELEN]);
+	legacy_ping.extend(vec![0x02; NODEID_BYTELEN]);
+	assert_eq!(split_version(&legacy_ping[..]), Some((LEGACY_PROTOCOL_VERSION, &legacy_ping[..])));
+	sock.send_to(&legacy_ping[..], super_addr).unwrap();
+
+	let mut resp = [0; 2048];
+	assert!(sock.recv_from(&mut resp).is_err());
+
+	// a Ping in our version is answered
+	let ping = Message::Ping(Ping {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+	});
+	let buf = add_version(NativeCodec.encode(&ping).unwrap());
+	sock.send_to(&buf[..], super_addr).unwrap();
+	let (len, _) = sock.recv_from(&mut resp).unwrap();
+	let (version, payload) = split_version(&resp[..len]).unwrap();
+	assert_eq!(version, PROTOCOL_VERSION);
+	match NativeCodec.decode(payload).unwrap() {
+		Message::Pong(_) => (),
+		msg => panic!("{:?}", msg),
+	}
+}
+
+#[test]
+fn test_delete() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	kad1.put_with_ttl(Key::new(zeros), vec![1,2,3], Duration::from_secs(60)).unwrap();
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![1,2,3]]);
+
+	// only its publisher can delete it
+	let mut kad2 = create_node(&handle, None, node_config());
+	add_supernode(&mut kad2, &kad_super);
+	assert_eq!(kad2.delete(Key::new(zeros), &[1,2,3]), 0);
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![1,2,3]]);
+
+	assert_eq!(kad1.delete(Key::new(zeros), &[1,2,3]), 1);
+	assert!(kad1.get(Key::new(zeros)).is_empty());
+	assert_eq!(kad1.republished_count(), 0);
+
+	// a late Store of the deleted value does not bring it back
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(0));
+	assert!(kad1.get(Key::new(zeros)).is_empty());
+}
+
+#[test]
+fn test_delete_signed() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+	let keypair = KeyPair::from_seed(&[0x01; 32]);
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	kad1.put_signed(Key::new(zeros), vec![1,2,3], &keypair).unwrap();
+	assert_eq!(kad1.delete(Key::new(zeros), &[1,2,3]), 0);
+	assert_eq!(kad1.delete_signed(Key::new(zeros), &[1,2,3], &KeyPair::from_seed(&[0x02; 32])), 0);
+	assert_eq!(kad1.get(Key::new(zeros)), vec![vec![1,2,3]]);
+
+	assert_eq!(kad1.delete_signed(Key::new(zeros), &[1,2,3], &keypair), 1);
+	assert!(kad1.get(Key::new(zeros)).is_empty());
+}
+
+#[test]
+fn test_handler_pool() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let config = KademliaConfig {
+		handler_threads: 4,
+		max_pending_messages: 16,
+		msg_rate_per_source: 0,
+		.. node_config()
+	};
+	let kad = create_node(&handle, None, config);
+	let addr = kad.local_addr().unwrap();
+	let threads = kad.thread_count();
+	assert_eq!(threads, 4);
+
+	let ping = Message::Ping(Ping {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+	});
+	let buf = add_version(NativeCodec.encode(&ping).unwrap());
+	let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+
+	for i in 0..10000 {
+		sock.send_to(&buf[..], addr).unwrap();
+		if i % 1000 == 0 {
+			assert_eq!(kad.thread_count(), threads);
+		}
+	}
+	assert_eq!(kad.thread_count(), threads);
+
+	// still answering
+	sleep(Duration::from_millis(500));
+	let other = create_node(&handle, None, node_config());
+	assert!(other.query_capabilities(addr).is_ok());
+}
+
+#[test]
+fn test_get_many() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	let keys:Vec<Key> = (1..5u8).map(|i| Key::new([i; NODEID_BYTELEN])).collect();
+	for (i, key) in keys.iter().take(3).enumerate() {
+		kad1.put(*key, vec![i as u8]).unwrap();
+	}
+
+	let found = kad1.get_many(keys.clone());
+	assert_eq!(found.len(), 4);
+	assert_eq!(found[&keys[0]], vec![vec![0]]);
+	assert_eq!(found[&keys[2]], vec![vec![2]]);
+	assert!(found[&keys[3]].is_empty());
+
+	assert!(kad1.get_many(vec![]).is_empty());
+}
+
+#[test]
+fn test_lookup_value_bytes() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let config = KademliaConfig {
+		max_lookup_value_bytes: 4,
+		.. node_config()
+	};
+	let (kad_super, mut kad1) = pair_with_config(&handle, config);
+	kad1.put(Key::new(zeros), vec![1,2,3]).unwrap();
+	kad1.put(Key::new(zeros), vec![4,5,6]).unwrap();
+
+	// the second value would exceed the 4 bytes
+	let result = kad1.get(Key::new(zeros));
+	assert_eq!(result.len(), 1);
+	assert_eq!(result[0].len(), 3);
+}
+
+#[test]
+fn test_composite_keys() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	let id = Node::id_from_components(&[b"namespace", b"type", b"id"]);
+	kad1.put(Key::new(id), vec![1,2,3]).unwrap();
+
+	// built independently on the other node
+	let id = Node::id_from_components(&[b"namespace", b"type", b"id"]);
+	assert_eq!(kad_super.get(Key::new(id)), vec![vec![1,2,3]]);
+}
+
+#[test]
+fn test_paused() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let zeros = [0x00; NODEID_BYTELEN];
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	kad_super.set_paused(true);
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(0));
+	assert!(kad_super.dropped_while_paused() > 0);
+	assert_eq!(kad_super.cached_value_publishers(&Key::new(zeros)).len(), 0);
+
+	kad_super.set_paused(false);
+	assert_eq!(kad1.put(Key::new(zeros), vec![1,2,3]), Ok(1));
+	assert_eq!(kad_super.cached_value_publishers(&Key::new(zeros)).len(), 1);
+}
+
+#[test]
+fn test_find_values() {
+	let core = Core::new().unwrap();
+	let handle = core.handle();
+
+	let (kad_super, mut kad1) = pair(&handle);
+
+	let keys:Vec<Key> = (1..6u8).map(|i| Key::new([i; NODEID_BYTELEN])).collect();
+	for (i, key) in keys.iter().enumerate() {
+		kad1.put(*key, vec![i as u8]).unwrap();
+	}
+
+	// a single request answered by a single datagram
+	let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+	sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+	let req = Message::FindValues(FindValues {
+		sender_id: [0x01; NODEID_BYTELEN],
+		cookie:    [0x02; NODEID_BYTELEN],
+		keys:      keys.iter().map(|k| k.id()).collect(),
+	});
+	let buf = add_version(NativeCodec.encode(&req).unwrap());
+	sock.send_to(&buf[..], kad_super.local_addr().unwrap()).unwrap();
+
+	let mut resp = [0; 2048];
+	let (len, _) = sock.recv_from(&mut resp).unwrap();
+	let (version, payload) = split_version(&resp[..len]).unwrap();
+	assert_eq!(version, PROTOCOL_VERSION);
+	match NativeCodec.decode(payload).unwrap() {
+		Message::FoundValues(found_values) => {
+			assert_eq!(found_values.entry_count, 5);
+			let values:Vec<Vec<u8>> = found_values.entries.into_iter().map(|(_, v, _)| v.data).collect();
+			assert_eq!(values, vec![vec![0], vec![1], vec![2], vec![3], vec![4]]);
+		},
+		msg => panic!("{:?}", msg),
+	}
+}
@@ -0,0 +1,221 @@
+use std::cmp;
+use std::sync::{Arc,Mutex};
+use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+
+use clock::{Duration, Instant, SharedClock};
+use message::{Message, Cookie};
+use node::{Node, NodeId};
+
+/// What happened during a lookup. `at` is the time since the lookup started.
+#[derive(Clone, Debug)]
+pub enum TraceEvent {
+	Query {
+		at:        Duration,
+		target:    SocketAddr,
+		target_id: NodeId,
+		cookie:    Cookie,
+	},
+	Response {
+		at:      Duration,
+		from:    NodeId,
+		kind:    &'static str,
+		/// the contact a FoundNode told us about
+		learned: Option<Node>,
+	},
+}
+
+/// Chronological record of a lookup for offline analysis
+#[derive(Clone, Debug)]
+pub struct LookupTrace {
+	pub key:    NodeId,
+	pub events: Vec<TraceEvent>,
+	pub result: Vec<Vec<u8>>,
+}
+
+/// Summary of a `LookupTrace`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct LookupStats {
+	/// distinct nodes we sent a query to
+	pub queried:   usize,
+	pub responded: usize,
+	/// queried nodes that never answered
+	pub timed_out: usize,
+	/// contacts we learned from FoundNode responses
+	pub learned:   usize,
+	/// how deep the lookup went: contacts from our routing table are
+	/// 1 hop away, contacts they told us about 2 hops, and so on
+	pub hops:      usize,
+	/// hops to the closest node that had a value (None if nobody had one)
+	pub value_hops: Option<usize>,
+}
+
+impl LookupTrace {
+	pub fn stats(&self) -> LookupStats {
+		let mut hops:HashMap<NodeId, usize> = HashMap::new();
+		let mut queried = HashSet::new();
+		let mut responded = HashSet::new();
+		let mut learned_ids = HashSet::new();
+		let mut value_hops = None;
+
+		for event in self.events.iter() {
+			match *event {
+				TraceEvent::Query { target_id, .. } => {
+					queried.insert(target_id);
+					hops.entry(target_id).or_insert(1);
+				},
+				TraceEvent::Response { from, kind, ref learned, .. } => {
+					let depth = *hops.get(&from).unwrap_or(&1);
+
+					if kind != "Timeout" {
+						responded.insert(from);
+					}
+					if kind == "FoundValue" {
+						value_hops = Some(value_hops.map_or(depth, |h| cmp::min(h, depth)));
+					}
+					if let Some(ref node) = *learned {
+						learned_ids.insert(node.node_id);
+						hops.entry(node.node_id).or_insert(depth + 1);
+					}
+				},
+			}
+		}
+
+		LookupStats {
+			queried:    queried.len(),
+			responded:  responded.len(),
+			timed_out:  queried.difference(&responded).count(),
+			learned:    learned_ids.len(),
+			hops:       queried.iter().filter_map(|id| hops.get(id)).cloned().max().unwrap_or(0),
+			value_hops: value_hops,
+		}
+	}
+}
+
+/// Records a `LookupTrace`, can be shared with the threads of a lookup
+#[derive(Clone)]
+pub struct Tracer {
+	started: Instant,
+	clock:   SharedClock,
+	trace:   Arc<Mutex<LookupTrace>>,
+}
+
+impl Tracer {
+	pub fn new(key: NodeId, clock: SharedClock) -> Tracer {
+		Tracer {
+			started: clock.now(),
+			clock:   clock,
+			trace:   Arc::new(Mutex::new(LookupTrace {
+				key:    key,
+				events: vec![],
+				result: vec![],
+			})),
+		}
+	}
+
+	fn elapsed(&self) -> Duration {
+		self.clock.now().duration_since(self.started)
+	}
+
+	pub fn query(&self, node: &Node, cookie: &Cookie) {
+		let event = TraceEvent::Query {
+			at:        self.elapsed(),
+			target:    node.addr,
+			target_id: node.node_id,
+			cookie:    *cookie,
+		};
+		self.trace.lock().unwrap().events.push(event);
+	}
+
+	pub fn response(&self, node: &Node, resp: &Message) {
+		let (kind, learned) = match *resp {
+			Message::FoundNode(ref found_node) => ("FoundNode", Some(found_node.node.clone())),
+			Message::FoundValue(_) => ("FoundValue", None),
+			Message::Timeout => ("Timeout", None),
+			_ => ("Other", None),
+		};
+
+		let event = TraceEvent::Response {
+			at:      self.elapsed(),
+			from:    node.node_id,
+			kind:    kind,
+			learned: learned,
+		};
+		self.trace.lock().unwrap().events.push(event);
+	}
+
+	pub fn finish(&self, result: Vec<Vec<u8>>) -> LookupTrace {
+		let mut trace = self.trace.lock().unwrap();
+		trace.result = result;
+		trace.clone()
+	}
+}
+
+#[cfg(test)]
+use clock::MockClock;
+#[cfg(test)]
+use node::NODEID_BYTELEN;
+#[cfg(test)]
+use message::{FoundNode, FoundValue, Value};
+
+#[test]
+fn chronological() {
+	let clock = MockClock::new();
+	let tracer = Tracer::new([0x00; NODEID_BYTELEN], Arc::new(clock.clone()));
+	let node = Node::new("127.0.0.1:2134", [0xff; NODEID_BYTELEN]).unwrap();
+
+	tracer.query(&node, &[0x01; NODEID_BYTELEN]);
+	clock.advance(Duration::from_millis(30));
+	tracer.response(&node, &Message::Timeout);
+
+	let trace = tracer.finish(vec![vec![1,2,3]]);
+	assert_eq!(trace.events.len(), 2);
+	assert_eq!(trace.result, vec![vec![1,2,3]]);
+
+	match trace.events[1] {
+		TraceEvent::Response { at, kind, .. } => {
+			assert_eq!(at, Duration::from_millis(30));
+			assert_eq!(kind, "Timeout");
+		},
+		_ => unreachable!(),
+	}
+}
+
+#[test]
+fn stats() {
+	let clock = MockClock::new();
+	let tracer = Tracer::new([0x00; NODEID_BYTELEN], Arc::new(clock.clone()));
+	let near = Node::new("127.0.0.1:2134", [0x01; NODEID_BYTELEN]).unwrap();
+	let nearer = Node::new("127.0.0.1:2135", [0x02; NODEID_BYTELEN]).unwrap();
+	let dead = Node::new("127.0.0.1:2136", [0x03; NODEID_BYTELEN]).unwrap();
+	let cookie = [0x04; NODEID_BYTELEN];
+
+	tracer.query(&near, &cookie);
+	tracer.query(&dead, &cookie);
+	tracer.response(&near, &Message::FoundNode(FoundNode {
+		sender_id:  near.node_id,
+		cookie:     cookie,
+		node_count: 1,
+		node:       nearer.clone(),
+	}));
+	tracer.response(&near, &Message::Timeout);
+	tracer.response(&dead, &Message::Timeout);
+
+	tracer.query(&nearer, &cookie);
+	tracer.response(&nearer, &Message::FoundValue(FoundValue {
+		sender_id:   nearer.node_id,
+		cookie:      cookie,
+		value_count: 1,
+		value:       Value { data: vec![1,2,3] },
+		signature:   None,
+	}));
+
+	assert_eq!(tracer.finish(vec![vec![1,2,3]]).stats(), LookupStats {
+		queried:    3,
+		responded:  2,
+		timed_out:  1,
+		learned:    1,
+		hops:       2,
+		value_hops: Some(2),
+	});
+}
@@ -1,7 +1,14 @@
 mod take_until;
 pub mod semaphore;
+pub mod thread_limit;
+pub mod rate_limit;
+pub mod worker_pool;
 
-use std::net::{SocketAddr,SocketAddrV4};
+use std::io;
+use std::net::{SocketAddr,SocketAddrV4,SocketAddrV6,IpAddr,UdpSocket,ToSocketAddrs};
+
+use net2::UdpBuilder;
+use get_if_addrs::get_if_addrs;
 
 pub fn ignore<R,E>(res: Result<R,E>) {
 	match res {
@@ -9,14 +16,110 @@ pub fn ignore<R,E>(res: Result<R,E>) {
 	}
 }
 
+/// Turns IPv4-mapped IPv6 addresses (::ffff:a.b.c.d, as seen by a socket
+/// bound to [::]) into plain IPv4 addresses
 pub fn ip4or6(addr: SocketAddr) -> SocketAddr {
 	match addr {
 		SocketAddr::V4(addr) => SocketAddr::V4(addr),
 		SocketAddr::V6(addr) => {
+				let s = addr.ip().segments();
+				let is_mapped = s[..5].iter().all(|&x| x == 0) && s[5] == 0xffff;
+
+				// to_ipv4() would turn ::1 into 0.0.0.1, too
 				match addr.ip().to_ipv4() {
-						None => SocketAddr::V6(addr),
-						Some(ip) => SocketAddr::V4(SocketAddrV4::new(ip, addr.port()))
+						Some(ip) if is_mapped => SocketAddr::V4(SocketAddrV4::new(ip, addr.port())),
+						_ => SocketAddr::V6(addr),
 				}
 		}
 	}
 }
+
+/// The reverse of `ip4or6()`: how to reach `addr` from an IPv6 socket
+pub fn ip6_mapped(addr: SocketAddr) -> SocketAddr {
+	match addr {
+		SocketAddr::V4(addr) => SocketAddr::V6(SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0)),
+		SocketAddr::V6(addr) => SocketAddr::V6(addr),
+	}
+}
+
+/// Like `UdpSocket::bind()`, but a socket bound to [::] is dual-stack
+/// (IPV6_V6ONLY is cleared, some systems set it by default)
+pub fn bind_udp<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+	let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to");
+
+	for addr in try!(addr.to_socket_addrs()) {
+		let sock = match addr {
+			SocketAddr::V4(_) => UdpSocket::bind(addr),
+			SocketAddr::V6(_) => UdpBuilder::new_v6()
+				.and_then(|builder| builder.only_v6(false).map(|_| builder))
+				.and_then(|builder| builder.bind(addr)),
+		};
+		match sock {
+			Ok(sock) => return Ok(sock),
+			Err(e) => last_err = e,
+		}
+	}
+	Err(last_err)
+}
+
+/// The addresses of our network interfaces (loopback included)
+pub fn interface_ips() -> Vec<IpAddr> {
+	match get_if_addrs() {
+		Ok(ifs) => ifs.into_iter().map(|i| i.ip()).collect(),
+		Err(e) => {
+			warn!("Cannot list our network interfaces: {}", e);
+			vec![]
+		},
+	}
+}
+
+/// Whether datagrams to `addr` reach a socket bound to `local`. If that is
+/// [::] or 0.0.0.0, they do at any of `own_ips` (see `interface_ips()`),
+/// IPv4 ones included for [::].
+pub fn is_own_addr(addr: &SocketAddr, local: &SocketAddr, own_ips: &[IpAddr]) -> bool {
+	let addr = ip4or6(*addr);
+	if addr.port() != local.port() {
+		return false;
+	}
+
+	match local.ip() {
+		IpAddr::V4(ip) if ip.is_unspecified() =>
+			addr.is_ipv4() && own_ips.contains(&addr.ip()),
+		IpAddr::V6(ip) if ip.is_unspecified() =>
+			own_ips.contains(&addr.ip()),
+		_ => addr == ip4or6(*local),
+	}
+}
+
+#[test]
+fn mapped_addresses() {
+	let v4:SocketAddr = "127.0.0.1:2134".parse().unwrap();
+	let mapped:SocketAddr = "[::ffff:127.0.0.1]:2134".parse().unwrap();
+	let loopback:SocketAddr = "[::1]:2134".parse().unwrap();
+
+	assert_eq!(ip4or6(mapped), v4);
+	assert_eq!(ip4or6(loopback), loopback);
+	assert_eq!(ip6_mapped(v4), mapped);
+	assert_eq!(ip6_mapped(loopback), loopback);
+}
+
+#[test]
+fn own_addresses() {
+	let own_ips:Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "192.168.1.2".parse().unwrap(),
+		"::1".parse().unwrap()];
+	let any4:SocketAddr = "0.0.0.0:2134".parse().unwrap();
+	let any6:SocketAddr = "[::]:2134".parse().unwrap();
+	let bound:SocketAddr = "192.168.1.2:2134".parse().unwrap();
+
+	// another host using the same port is not us
+	assert!(!is_own_addr(&"192.168.1.3:2134".parse().unwrap(), &any4, &own_ips));
+	assert!(is_own_addr(&"192.168.1.2:2134".parse().unwrap(), &any4, &own_ips));
+	assert!(!is_own_addr(&"192.168.1.2:2135".parse().unwrap(), &any4, &own_ips));
+	assert!(!is_own_addr(&"[::1]:2134".parse().unwrap(), &any4, &own_ips));
+
+	assert!(is_own_addr(&"[::1]:2134".parse().unwrap(), &any6, &own_ips));
+	assert!(is_own_addr(&"[::ffff:192.168.1.2]:2134".parse().unwrap(), &any6, &own_ips));
+
+	assert!(is_own_addr(&bound, &bound, &own_ips));
+	assert!(!is_own_addr(&"127.0.0.1:2134".parse().unwrap(), &bound, &own_ips));
+}
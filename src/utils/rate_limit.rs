@@ -0,0 +1,127 @@
+use std::sync::{Arc,Mutex};
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use clock::{Instant, SharedClock};
+
+/// Forget sources once we track this many, see `evict()`
+const MAX_SOURCES: usize = 4096;
+
+struct Bucket {
+	tokens:  f64,
+	updated: Instant,
+}
+
+/// A token bucket per source address: each message takes a token, tokens
+/// refill at `rate` per second up to `burst`
+#[derive(Clone)]
+pub struct RateLimiter {
+	buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+	rate:    f64,
+	burst:   f64,
+	clock:   SharedClock,
+}
+
+impl RateLimiter {
+	/// A `rate` of 0 disables the limit
+	pub fn new(rate: u32, burst: u32, clock: SharedClock) -> RateLimiter {
+		RateLimiter {
+			buckets: Arc::new(Mutex::new(HashMap::new())),
+			rate:    rate as f64,
+			burst:   burst as f64,
+			clock:   clock,
+		}
+	}
+
+	/// Takes a token for a message from `src`, false if there is none left
+	pub fn allow(&self, src: IpAddr) -> bool {
+		if self.rate == 0.0 {
+			return true;
+		}
+
+		let mut buckets = self.buckets.lock().unwrap();
+		// only now, no other thread can store a later `updated` meanwhile
+		let now = self.clock.now();
+
+		if buckets.len() >= MAX_SOURCES && !buckets.contains_key(&src) {
+			evict(&mut buckets);
+		}
+
+		let bucket = buckets.entry(src).or_insert(Bucket {
+			tokens:  self.burst,
+			updated: now,
+		});
+		bucket.tokens = refilled(bucket, now, self.rate, self.burst);
+		bucket.updated = now;
+
+		if bucket.tokens < 1.0 {
+			return false;
+		}
+		bucket.tokens -= 1.0;
+		true
+	}
+}
+
+/// Forgets the quarter of the sources we heard from least recently, so a
+/// flood of new (e.g. spoofed) sources cannot keep out the others, and
+/// this only happens every MAX_SOURCES/4 new sources
+fn evict(buckets: &mut HashMap<IpAddr, Bucket>) {
+	let mut updated:Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+	updated.sort();
+	let oldest_kept = updated[updated.len() / 4];
+
+	buckets.retain(|_, b| b.updated >= oldest_kept);
+}
+
+fn refilled(bucket: &Bucket, now: Instant, rate: f64, burst: f64) -> f64 {
+	let dt = now.duration_since(bucket.updated);
+	let dt = dt.as_secs() as f64 + dt.subsec_nanos() as f64 / 1e9;
+
+	(bucket.tokens + rate * dt).min(burst)
+}
+
+#[cfg(test)]
+use clock::{Duration, MockClock};
+
+#[test]
+fn token_bucket() {
+	let clock = MockClock::new();
+	let limiter = RateLimiter::new(10, 5, Arc::new(clock.clone()));
+	let a = "127.0.0.1".parse().unwrap();
+	let b = "127.0.0.2".parse().unwrap();
+
+	for _ in 0..5 {
+		assert!(limiter.allow(a));
+	}
+	assert!(!limiter.allow(a));
+	// sources are limited independently
+	assert!(limiter.allow(b));
+
+	clock.advance(Duration::from_millis(100));
+	assert!(limiter.allow(a));
+	assert!(!limiter.allow(a));
+
+	assert!(RateLimiter::new(0, 0, Arc::new(clock.clone())).allow(a));
+}
+
+#[test]
+fn evicts_oldest() {
+	let clock = MockClock::new();
+	let limiter = RateLimiter::new(1, 1, Arc::new(clock.clone()));
+	let a:IpAddr = "10.0.0.1".parse().unwrap();
+
+	assert!(limiter.allow(a));
+	assert!(!limiter.allow(a));
+
+	// other sources that did not refill yet either
+	for i in 1..MAX_SOURCES as u32 {
+		clock.advance(Duration::from_millis(1));
+		assert!(limiter.allow(IpAddr::V4((0x0b000000 + i).into())));
+	}
+	clock.advance(Duration::from_millis(1));
+	assert!(limiter.allow("12.0.0.1".parse().unwrap()));
+
+	let buckets = limiter.buckets.lock().unwrap();
+	assert!(buckets.len() < MAX_SOURCES);
+	assert!(!buckets.contains_key(&a));
+}
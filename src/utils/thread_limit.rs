@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts active worker threads and refuses new ones above a limit
+#[derive(Clone)]
+pub struct ThreadLimit {
+	active: Arc<AtomicUsize>,
+	max:    usize,
+}
+
+/// Counts as one active thread until dropped
+pub struct ThreadGuard {
+	active: Arc<AtomicUsize>,
+}
+
+impl ThreadLimit {
+	pub fn new(max: usize) -> ThreadLimit {
+		ThreadLimit {
+			active: Arc::new(AtomicUsize::new(0)),
+			max:    max,
+		}
+	}
+
+	/// Returns None if `max` threads are active already
+	pub fn try_acquire(&self) -> Option<ThreadGuard> {
+		loop {
+			let active = self.active.load(Ordering::SeqCst);
+			if active >= self.max {
+				return None;
+			}
+
+			if self.active.compare_and_swap(active, active + 1, Ordering::SeqCst) == active {
+				return Some(ThreadGuard { active: self.active.clone() });
+			}
+		}
+	}
+
+	pub fn active(&self) -> usize {
+		self.active.load(Ordering::SeqCst)
+	}
+}
+
+impl Drop for ThreadGuard {
+	fn drop(&mut self) {
+		self.active.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[test]
+fn limit() {
+	let limit = ThreadLimit::new(2);
+
+	let a = limit.try_acquire();
+	let b = limit.try_acquire();
+	assert!(a.is_some() && b.is_some());
+	assert!(limit.try_acquire().is_none());
+	assert_eq!(limit.active(), 2);
+
+	drop(a);
+	assert_eq!(limit.active(), 1);
+	assert!(limit.try_acquire().is_some());
+}
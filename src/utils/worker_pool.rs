@@ -0,0 +1,155 @@
+use std::io;
+use std::mem;
+use std::thread::{self, JoinHandle};
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{self, SyncSender, Receiver, TrySendError};
+
+use utils::ignore;
+use utils::thread_limit::ThreadLimit;
+
+/// A fixed number of threads handling the jobs of a bounded queue.
+/// The threads stop once the pool (and all its clones) are dropped.
+#[derive(Clone)]
+pub struct WorkerPool<T: Send + 'static> {
+	tx: SyncSender<T>,
+	workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+	/// Starts up to `threads` workers running `handler`, each counting as
+	/// an active thread of `limit` (fewer if it is reached, Err if not even
+	/// one may start). At most `queue_len` jobs wait for them.
+	pub fn new<F>(threads: usize, queue_len: usize, limit: &ThreadLimit, handler: F) -> io::Result<WorkerPool<T>>
+		where F: Fn(T) + Send + Sync + 'static
+	{
+		let (tx, rx) = mpsc::sync_channel(queue_len);
+		let rx = Arc::new(Mutex::new(rx));
+		let handler = Arc::new(handler);
+		let mut workers = vec![];
+
+		for _ in 0..threads {
+			let guard = match limit.try_acquire() {
+				Some(guard) => guard,
+				None => break,
+			};
+			let rx = rx.clone();
+			let handler = handler.clone();
+
+			workers.push(thread::spawn(move || {
+				let _guard = guard;
+				while let Some(job) = next_job(&rx) {
+					handler(job);
+				}
+			}));
+		}
+
+		if workers.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::Other, "Thread limit reached"));
+		}
+		if workers.len() < threads {
+			debug!("Thread limit reached, starting {} of {} workers", workers.len(), threads);
+		}
+
+		Ok(WorkerPool {
+			tx:      tx,
+			workers: Arc::new(Mutex::new(workers)),
+		})
+	}
+
+	/// Stops taking jobs and waits until the workers handled the queued ones
+	/// (and dropped `handler`). Only returns once all clones are dropped.
+	pub fn join(self) {
+		let workers = mem::replace(&mut *self.workers.lock().unwrap(), vec![]);
+		drop(self);
+
+		for worker in workers {
+			ignore(worker.join());
+		}
+	}
+
+	/// Queues `job`, or hands it back if the queue is full
+	pub fn try_submit(&self, job: T) -> Result<(), T> {
+		match self.tx.try_send(job) {
+			Ok(()) => Ok(()),
+			Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job),
+		}
+	}
+}
+
+/// Only one worker waits on the queue at a time, the others wait for the lock
+fn next_job<T>(rx: &Arc<Mutex<Receiver<T>>>) -> Option<T> {
+	rx.lock().unwrap().recv().ok()
+}
+
+#[cfg(test)]
+use std::time::Duration;
+
+#[test]
+fn bounded_queue() {
+	let limit = ThreadLimit::new(8);
+	let (done_tx, done_rx) = mpsc::channel();
+	let done_tx = Mutex::new(done_tx);
+	let (block_tx, block_rx) = mpsc::channel::<()>();
+	let block_rx = Mutex::new(block_rx);
+
+	// both workers block on their first job
+	let pool = WorkerPool::new(2, 1, &limit, move |i: usize| {
+		if i < 2 {
+			let _ = block_rx.lock().unwrap().recv();
+		}
+		done_tx.lock().unwrap().send(i).unwrap();
+	}).unwrap();
+	assert_eq!(limit.active(), 2);
+
+	for i in 0..2 {
+		assert!(pool.try_submit(i).is_ok());
+		thread::sleep(Duration::from_millis(100));
+	}
+	assert!(pool.try_submit(2).is_ok());
+	assert_eq!(pool.try_submit(3), Err(3));
+
+	drop(block_tx);
+	let mut done:Vec<usize> = (0..3).map(|_| done_rx.recv().unwrap()).collect();
+	done.sort();
+	assert_eq!(done, vec![0, 1, 2]);
+
+	drop(pool);
+	thread::sleep(Duration::from_millis(100));
+	assert_eq!(limit.active(), 0);
+}
+
+#[test]
+fn join() {
+	let limit = ThreadLimit::new(8);
+	let (done_tx, done_rx) = mpsc::channel();
+	let done_tx = Mutex::new(done_tx);
+
+	let pool = WorkerPool::new(2, 4, &limit, move |i: usize| {
+		thread::sleep(Duration::from_millis(50));
+		done_tx.lock().unwrap().send(i).unwrap();
+	}).unwrap();
+	for i in 0..4 {
+		assert!(pool.try_submit(i).is_ok());
+	}
+
+	// all queued jobs are handled before join() returns
+	pool.join();
+	assert_eq!(done_rx.try_iter().count(), 4);
+	assert_eq!(limit.active(), 0);
+}
+
+#[test]
+fn thread_limit() {
+	let limit = ThreadLimit::new(3);
+	let taken = limit.try_acquire();
+
+	// only the threads the limit allows
+	let pool = WorkerPool::new(4, 1, &limit, |_: usize| ()).unwrap();
+	assert_eq!(limit.active(), 3);
+	assert!(WorkerPool::new(1, 1, &limit, |_: usize| ()).is_err());
+	assert_eq!(limit.active(), 3);
+
+	pool.join();
+	drop(taken);
+	assert_eq!(limit.active(), 0);
+}